@@ -1,18 +1,29 @@
 //! # Description
-//! 
-//! This binary is a small executable that tests the basic functionality of 
-//! the `throrgan` crate. This binary is not intended to be published, and if
-//! you are reading this, it was likely published accidentally.
-use throrgan;
+//!
+//! This binary is the `throrgan` command line tool. It compiles `.thr` files
+//! into audio files, checks them for errors, summarizes them and plays them.
+//! Run `throrgan --help` for its usage.
+//!
+//! The exit code is 0 on success, 1 if the song could not be read or
+//! compiled, 2 if the command line is invalid or asks for output that cannot
+//! be written, and 3 if the output file already exists and `--force` was not
+//! given.
+
+use throrgan::{Diagnostics, FileFormat, Normalization, Options, Overwrite,
+    Renderer, SampleFormat, Severity, ThrorganError};
 
 use std::{
+    env,
+    error::Error,
+    fs,
+    path::Path,
+    process,
     thread,
     time::Duration
 };
 use rg3d_sound::{
     source::{
         generic::GenericSourceBuilder,
-        SoundSource,
         Status
     },
     context::Context,
@@ -20,24 +31,284 @@ use rg3d_sound::{
         DataSource,
         SoundBuffer
     },
+    engine::SoundEngine,
 };
 
+const USAGE: &str = "\
+Usage: throrgan <command> <input> [options]
+
+Commands:
+  compile    Compile the song into an audio file
+  check      Check the song for errors without compiling it
+  info       Summarize the song
+  play       Compile the song and play it
+
+Options:
+  -o, --output <file>        Output file, by default the input with a .wav
+                             extension
+  -f, --force                Overwrite the output file if it exists
+      --format <format>      wav, flac, ogg or raw, by default chosen by the
+                             extension of the output
+      --sample-rate <hz>     Samples per second, 44100 by default
+      --sample-format <fmt>  i16, i24 or f32, i16 by default
+      --channels <n>         1 for mono or 2 for stereo, 1 by default
+      --renderer <name>      spectral or oscillator, spectral by default
+      --normalize <target>   peak:<dBFS> or lufs:<LUFS>
+      --no-limiter           Let samples clip instead of limiting them
+      --no-dither            Round to integer samples without dither
+      --strict               Reject notes written out of order
+  -h, --help                 Print this message
+  -V, --version              Print the version";
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_EXISTS: i32 = 3;
+
+enum Command {
+    Compile,
+    Check,
+    Info,
+    Play,
+}
+
+/// Everything read from the command line.
+struct Args {
+    command: Command,
+    input: String,
+    output: Option<String>,
+    options: Options,
+}
+
+/// Read the value of an option such as `--sample-rate 48000`.
+fn value<'a>(args: &mut impl Iterator<Item = &'a String>, flag: &str)
+-> Result<&'a String, String> {
+    args.next().ok_or(format!("{} needs a value", flag))
+}
+
+/// Read a number from the value of `flag`.
+fn number<T: std::str::FromStr>(text: &str, flag: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("Invalid value {} for {}", text, flag))
+}
+
+/// Read the command line, not counting the name of the program.
+fn parse_args(args: &[String]) -> Result<Args, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(|c| c.as_str()) {
+        Some("compile") => Command::Compile,
+        Some("check") => Command::Check,
+        Some("info") => Command::Info,
+        Some("play") => Command::Play,
+        Some(c) => return Err(format!("Unknown command {}", c)),
+        None => return Err("No command given".to_string()),
+    };
+
+    let mut input = None;
+    let mut output = None;
+    let mut options = Options::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(value(&mut args, arg)?.clone()),
+            "-f" | "--force" => options.overwrite = Overwrite::Always,
+            "--format" => options.file_format = Some(
+                match value(&mut args, arg)?.as_str() {
+                    "wav" => FileFormat::Wav,
+                    "flac" => FileFormat::Flac,
+                    "ogg" => FileFormat::Ogg,
+                    "raw" => FileFormat::Raw,
+                    f => return Err(format!("Unknown format {}", f)),
+                }),
+            "--sample-rate" => options.sample_rate = number(
+                value(&mut args, arg)?, arg)?,
+            "--sample-format" => options.sample_format =
+                match value(&mut args, arg)?.as_str() {
+                    "i16" => SampleFormat::Int16,
+                    "i24" => SampleFormat::Int24,
+                    "f32" => SampleFormat::Float32,
+                    f => return Err(format!("Unknown sample format {}", f)),
+                },
+            "--channels" => options.channels = number(
+                value(&mut args, arg)?, arg)?,
+            "--renderer" => options.renderer =
+                match value(&mut args, arg)?.as_str() {
+                    "spectral" => Renderer::Spectral,
+                    "oscillator" => Renderer::Oscillator,
+                    r => return Err(format!("Unknown renderer {}", r)),
+                },
+            "--normalize" => {
+                let target = value(&mut args, arg)?;
+                options.normalization = match target.split_once(':') {
+                    Some(("peak", db)) => Normalization::Peak(number(db, arg)?),
+                    Some(("lufs", lufs)) => Normalization::Loudness(
+                        number(lufs, arg)?),
+                    _ => return Err(format!("Invalid value {} for {}", target,
+                        arg)),
+                };
+            },
+            "--no-limiter" => options.limiter = false,
+            "--no-dither" => options.dither = false,
+            "--strict" => options.strict = true,
+            a if a.starts_with('-') => return Err(
+                format!("Unknown option {}", a)),
+            a if input.is_none() => input = Some(a.to_string()),
+            a => return Err(format!("Unexpected argument {}", a)),
+        }
+    }
+
+    let input = input.ok_or("No input file given")?;
+    Ok(Args { command, input, output, options })
+}
+
+/// Get the output file for `input` when none is given: the input with its
+/// extension replaced by that of the output format.
+fn default_output(input: &str, options: &Options) -> String {
+    let extension = match options.file_format {
+        Some(FileFormat::Flac) => "flac",
+        Some(FileFormat::Ogg) => "ogg",
+        Some(FileFormat::Raw) => "raw",
+        Some(FileFormat::Wav) | None => "wav",
+    };
+    Path::new(input).with_extension(extension).to_string_lossy().into_owned()
+}
+
+/// Compile the song in memory and play it on the default output device,
+/// returning once it has finished.
+fn play(input: &str, options: &Options) -> Result<(), Box<dyn Error>> {
+    let source = fs::read_to_string(input)
+        .map_err(|e| ThrorganError::read(input, e))?;
+    let (song, warnings) = throrgan::compile_str(&source, options)?;
+    print_warnings(&warnings);
+
+    let engine = SoundEngine::new();
+    let context = Context::new();
+    engine.lock().unwrap().add_context(context.clone());
+
+    let buffer = SoundBuffer::new_generic(DataSource::Raw {
+        sample_rate: song.sample_rate as usize,
+        channel_count: song.channels as usize,
+        samples: song.samples,
+    }).map_err(|_| "Could not load the song for playing")?;
+    let source = GenericSourceBuilder::new(buffer)
+        .with_status(Status::Playing)
+        .build_source()
+        .map_err(|e| e.to_string())?;
+    let handle = context.state().add_source(source);
+    while context.state().source(handle).status() == Status::Playing {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    Ok(())
+}
+
+/// Print the warnings found in a song, if there are any.
+fn print_warnings(warnings: &Diagnostics) {
+    if !warnings.problems().is_empty() {
+        eprintln!("{}\n", warnings);
+    }
+}
+
+/// Print `error` and the chain of errors that caused it. When the song has
+/// errors, every problem found is printed.
+fn report(error: &(dyn Error + 'static)) {
+    if let Some(ThrorganError::Diagnostics(diagnostics)) =
+        error.downcast_ref::<ThrorganError>() {
+        eprintln!("{}\n", diagnostics);
+        eprintln!("{} error(s) and {} warning(s) found",
+            diagnostics.count(Severity::Error),
+            diagnostics.count(Severity::Warning));
+        return;
+    }
+    eprintln!("error: {}", error);
+    let mut cause = error.source();
+    while let Some(e) = cause {
+        eprintln!("caused by: {}", e);
+        cause = e.source();
+    }
+}
+
+/// Carry out the command and get the exit code.
+fn run(args: Args) -> i32 {
+    let result: Result<(), Box<dyn Error>> = match args.command {
+        Command::Compile => {
+            let output = args.output.clone().unwrap_or_else(
+                || default_output(&args.input, &args.options));
+            throrgan::compile_with(&args.input, &output, &args.options)
+                .map(|warnings| print_warnings(&warnings)).map_err(Box::from)
+        },
+        Command::Check => throrgan::check(&args.input, &args.options)
+            .map(|warnings| print_warnings(&warnings)).map_err(Box::from),
+        Command::Info => throrgan::info(&args.input, &args.options)
+            .map(|info| {
+                println!("Instruments: {}", info.instruments);
+                println!("Notes:       {}", info.notes);
+                println!("Tempo:       {} BPM", info.tempo);
+                println!("Bars:        {}", info.bars);
+                println!("Length:      {:.2} s", info.seconds);
+            }).map_err(Box::from),
+        Command::Play => play(&args.input, &args.options),
+    };
+    match result {
+        Ok(()) => 0,
+        Err(e) => match e.downcast_ref::<ThrorganError>() {
+            Some(ThrorganError::FileAlreadyExists { .. }) => {
+                eprintln!("error: {} Use --force to overwrite it.", e);
+                EXIT_EXISTS
+            },
+            Some(ThrorganError::InvalidOption(_)) => {
+                eprintln!("error: {}", e);
+                EXIT_USAGE
+            },
+            _ => {
+                report(e.as_ref());
+                EXIT_ERROR
+            },
+        },
+    }
+}
+
 fn main() {
-    throrgan::compile("foo.txt", "bar.wav").unwrap();
-    
-    // This currently doesn't work.
-    /*let context = Context::new();
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    if args.iter().any(|a| a == "-V" || a == "--version") {
+        println!("throrgan {}", env!("CARGO_PKG_VERSION"));
+        return;
+    }
+    process::exit(exit_code(&args));
+}
 
-    let sound_buffer = SoundBuffer::new_generic(
-        DataSource::from_file("bar.wav").unwrap()).unwrap();
+/// Read the command line and carry out the command, getting the exit code.
+fn exit_code(args: &[String]) -> i32 {
+    match parse_args(args) {
+        Ok(args) => run(args),
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            EXIT_USAGE
+        },
+    }
+}
 
-    let source = GenericSourceBuilder::new(sound_buffer)
-    .with_status(Status::Playing)
-    .build_source()
-    .unwrap();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    context.state()
-    .add_source(source);
+    /// Run the command line `line` and get its exit code.
+    fn run_line(line: &str) -> i32 {
+        let args: Vec<String> = line.split_whitespace().map(String::from)
+            .collect();
+        exit_code(&args)
+    }
 
-    thread::sleep(Duration::from_secs(3));*/
-}
\ No newline at end of file
+    #[test]
+    fn invalid_options() {
+        // Options are checked before the song is read, so it need not exist
+        for &options in &["--sample-rate 5", "--channels 3",
+            "--format flac --sample-format f32", "-o song.mp3",
+            "--sample-rate x", "--frobnicate"] {
+            assert_eq!(run_line(&format!("compile missing.thr {}", options)),
+                EXIT_USAGE, "{}", options);
+        }
+        assert_eq!(run_line("compile missing.thr"), EXIT_ERROR);
+    }
+}
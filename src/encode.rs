@@ -0,0 +1,286 @@
+//! # Encode
+//!
+//! This file holds the file formats a song can be written in. `Output` does
+//! the gain staging and hands each encoder finished samples, which the encoder
+//! lays out in its format.
+
+use crate::flac::Flac;
+use crate::options::{FileFormat, SampleFormat};
+use crate::vorbis::Vorbis;
+use std::io::{self, Seek, SeekFrom, Write};
+
+/// A run of interleaved samples ready to be stored. Integer samples use the
+/// full range of the bit depth of the output.
+pub enum Samples {
+    Int(Vec<i32>),
+    Float(Vec<f32>),
+}
+
+/// Writes samples in one file format.
+pub trait Encoder {
+    /// Append interleaved samples to the file.
+    fn write(&mut self, samples: &Samples) -> io::Result<()>;
+
+    /// Complete the file, filling in anything that depends on its length.
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// Describes the stream being encoded.
+#[derive(Clone, Copy)]
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub format: SampleFormat,
+}
+
+impl StreamInfo {
+    /// Get the number of bits stored for each sample.
+    pub fn bits(&self) -> u16 {
+        match self.format {
+            SampleFormat::Int16 => 16,
+            SampleFormat::Int24 => 24,
+            SampleFormat::Float32 => 32,
+        }
+    }
+}
+
+/// Make the encoder for `format` writing to `out`.
+pub fn new_encoder<'a, W: Write + Seek + 'a>(format: FileFormat, out: W,
+    info: StreamInfo) -> io::Result<Box<dyn Encoder + 'a>> {
+    Ok(match format {
+        FileFormat::Wav => Box::new(Wav::new(out, info)?),
+        FileFormat::Flac => Box::new(Flac::new(out, info)?),
+        FileFormat::Ogg => Box::new(Vorbis::new(out, info)?),
+        FileFormat::Raw => Box::new(Raw { out, info }),
+    })
+}
+
+/// Write the bytes of each sample in little-endian order.
+fn write_le<W: Write>(out: &mut W, samples: &Samples, info: &StreamInfo)
+-> io::Result<()> {
+    match samples {
+        Samples::Int(samples) => {
+            let width = info.bits() as usize / 8;
+            for sample in samples {
+                out.write_all(&sample.to_le_bytes()[..width])?;
+            }
+        },
+        Samples::Float(samples) => {
+            for sample in samples {
+                out.write_all(&sample.to_le_bytes())?;
+            }
+        },
+    }
+    Ok(())
+}
+
+/// Get the number of samples in each channel of `samples`.
+fn frame_count(samples: &Samples, info: &StreamInfo) -> u32 {
+    let len = match samples {
+        Samples::Int(samples) => samples.len(),
+        Samples::Float(samples) => samples.len(),
+    };
+    (len / info.channels as usize) as u32
+}
+
+/// Tail of the GUID of the sub-format in an extensible format chunk. The
+/// GUID starts with the format code of the samples.
+const SUBFORMAT_GUID: [u8; 14] = [0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80,
+    0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71];
+
+/// A RIFF .wav file. The header is written first and its chunk sizes are
+/// patched once the length is known.
+pub struct Wav<W: Write + Seek> {
+    out: W,
+    info: StreamInfo,
+    frames: u32, // Number of samples written to each channel so far
+}
+
+impl<W: Write + Seek> Wav<W> {
+    pub fn new(out: W, info: StreamInfo) -> io::Result<Wav<W>> {
+        let mut wav = Wav { out, info, frames: 0 };
+        wav.write_header()?;
+        Ok(wav)
+    }
+
+    /// Get the size of the sample data, which is followed by a pad byte if it
+    /// is odd.
+    fn data_size(&self) -> u32 {
+        self.frames * self.info.channels as u32 * self.info.bits() as u32 / 8
+    }
+
+    /// Write the RIFF header. The chunk sizes are those of the data written so
+    /// far, so this is called once with empty sizes and again by `finish`.
+    /// Anything but 16-bit samples uses the extensible format chunk, and float
+    /// data also needs a `fact` chunk.
+    fn write_header(&mut self) -> io::Result<()> {
+        let float = self.info.format == SampleFormat::Float32;
+        let extensible = self.info.format != SampleFormat::Int16;
+        let audio_format: u16 = if float { 3 } else { 1 };
+        let bits = self.info.bits();
+        let channels = self.info.channels;
+        let sample_rate = self.info.sample_rate;
+        let block_align = channels * bits / 8;
+        let data_size = self.data_size();
+        let fmt_size: u32 = if extensible { 40 } else { 16 };
+        let fact_size = if float { 12 } else { 0 };
+        let riff_size = 4 + 8 + fmt_size + fact_size + 8 + data_size +
+            data_size % 2;
+
+        let f = &mut self.out;
+        f.write_all(b"RIFF")?;
+        f.write_all(&riff_size.to_le_bytes())?;
+        f.write_all(b"WAVE")?;
+        f.write_all(b"fmt ")?;
+        f.write_all(&fmt_size.to_le_bytes())?;
+        f.write_all(&(if extensible { 0xFFFE } else { audio_format })
+            .to_le_bytes())?;
+        f.write_all(&channels.to_le_bytes())?;
+        f.write_all(&sample_rate.to_le_bytes())?;
+        f.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        f.write_all(&block_align.to_le_bytes())?;
+        f.write_all(&bits.to_le_bytes())?;
+        if extensible {
+            f.write_all(&22u16.to_le_bytes())?; // Size of the extension
+            f.write_all(&bits.to_le_bytes())?; // Every bit is used
+            // Front center for mono, front left and right for stereo
+            let mask: u32 = if channels == 1 { 0x4 } else { 0x3 };
+            f.write_all(&mask.to_le_bytes())?;
+            f.write_all(&audio_format.to_le_bytes())?;
+            f.write_all(&SUBFORMAT_GUID)?;
+        }
+        if float {
+            f.write_all(b"fact")?;
+            f.write_all(&4u32.to_le_bytes())?;
+            f.write_all(&self.frames.to_le_bytes())?;
+        }
+        f.write_all(b"data")?;
+        f.write_all(&data_size.to_le_bytes())
+    }
+}
+
+impl<W: Write + Seek> Encoder for Wav<W> {
+    fn write(&mut self, samples: &Samples) -> io::Result<()> {
+        write_le(&mut self.out, samples, &self.info)?;
+        self.frames += frame_count(samples, &self.info);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.data_size() % 2 == 1 {
+            self.out.write_all(&[0])?; // Chunks start on even bytes
+        }
+        self.out.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+/// Collects float samples in memory instead of encoding them.
+pub struct Collect<'a> {
+    pub samples: &'a mut Vec<f32>,
+}
+
+impl Encoder for Collect<'_> {
+    fn write(&mut self, samples: &Samples) -> io::Result<()> {
+        match samples {
+            Samples::Float(samples) => self.samples.extend(samples),
+            Samples::Int(_) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "Expected float samples")),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Headerless little-endian PCM. Players need to be told the sample rate,
+/// channel count and sample format separately.
+pub struct Raw<W: Write> {
+    out: W,
+    info: StreamInfo,
+}
+
+impl<W: Write> Encoder for Raw<W> {
+    fn write(&mut self, samples: &Samples) -> io::Result<()> {
+        write_le(&mut self.out, samples, &self.info)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Write `samples` to a .wav file in memory.
+    fn wav(samples: Samples, channels: u16, format: SampleFormat) -> Vec<u8> {
+        let info = StreamInfo { sample_rate: 48_000, channels, format };
+        let mut out = Cursor::new(Vec::new());
+        let mut wav = Wav::new(&mut out, info).unwrap();
+        wav.write(&samples).unwrap();
+        wav.finish().unwrap();
+        out.into_inner()
+    }
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2],
+            bytes[at + 3]])
+    }
+
+    #[test]
+    fn pcm_header() {
+        let bytes = wav(Samples::Int(vec![1, -1, 2]), 1, SampleFormat::Int16);
+        assert_eq!(bytes.len(), 44 + 6);
+        assert_eq!(u32_at(&bytes, 4), 36 + 6);
+        assert_eq!(&bytes[12..16], b"fmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        assert_eq!(u16_at(&bytes, 20), 1);
+        assert_eq!(u32_at(&bytes, 28), 96_000); // Bytes per second
+        assert_eq!(u16_at(&bytes, 32), 2); // Block align
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 6);
+    }
+
+    #[test]
+    fn extensible_header() {
+        // Three 24-bit samples make an odd data chunk, which is padded
+        let bytes = wav(Samples::Int(vec![1, -1, 2]), 1, SampleFormat::Int24);
+        assert_eq!(bytes.len(), 68 + 9 + 1);
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(u32_at(&bytes, 16), 40);
+        assert_eq!(u16_at(&bytes, 20), 0xFFFE);
+        assert_eq!(u16_at(&bytes, 34), 24);
+        assert_eq!(u16_at(&bytes, 36), 22);
+        assert_eq!(u16_at(&bytes, 38), 24);
+        assert_eq!(u32_at(&bytes, 40), 0x4);
+        assert_eq!(u16_at(&bytes, 44), 1);
+        assert_eq!(&bytes[46..60], &SUBFORMAT_GUID);
+        assert_eq!(&bytes[60..64], b"data");
+        assert_eq!(u32_at(&bytes, 64), 9);
+        assert_eq!(bytes[77], 0);
+
+        let bytes = wav(Samples::Float(vec![0.5, -0.5, 0.25, -0.25]), 2,
+            SampleFormat::Float32);
+        assert_eq!(bytes.len(), 80 + 16);
+        assert_eq!(u32_at(&bytes, 4), bytes.len() as u32 - 8);
+        assert_eq!(u16_at(&bytes, 20), 0xFFFE);
+        assert_eq!(u16_at(&bytes, 32), 8);
+        assert_eq!(u32_at(&bytes, 40), 0x3);
+        assert_eq!(u16_at(&bytes, 44), 3);
+        assert_eq!(&bytes[60..64], b"fact");
+        assert_eq!(u32_at(&bytes, 68), 2);
+        assert_eq!(&bytes[72..76], b"data");
+        assert_eq!(u32_at(&bytes, 76), 16);
+    }
+}
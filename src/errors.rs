@@ -1,55 +1,358 @@
-use std::{error, fmt};
+//! # Errors
+//!
+//! This file holds `ThrorganError`, the error returned by every fallible
+//! function of the crate. Problems found in a .thr, .inst or Scala file carry
+//! the file, line and columns they were found at, and print the offending
+//! line with the problem underlined. Files are read to the end even after a
+//! bad line, and `Diagnostics` gathers every error and warning found.
 
+use std::{error, fmt, io};
+use std::str::FromStr;
 
-pub type Result<T> = std::result::Result<T, Box<dyn error::Error + 'static>>;
+pub type Result<T> = std::result::Result<T, ThrorganError>;
 
+/// An error underlying another one
+type Cause = Box<dyn error::Error + Send + Sync + 'static>;
+
+/// The problems that can be found in a .thr, .inst or Scala file
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    InvalidMode,
+    NoModeDeclared,
+    InvalidSound,
+    ModeNotHit,
+    KeyWithoutValue,
+    InvalidValue,
+    /// A note in the given bar starts before the note written above it
+    InvalidNoteOrder(u64),
+    /// The given bar does not have the beat asked for
+    InvalidBeat(u64),
+    /// The given bar lies past `MAX_TICK`. Onsets in beats that lie past it
+    /// are `InvalidValue`, since they are too large to find the bar of.
+    PastEnd(u64),
+    /// A note in the given bar has a length that is not a positive number of
+    /// beats
+    InvalidLength(u64),
+    InvalidKey,
+    UnknownInstrument,
+    /// A value at the end of a line that is not read
+    ExtraValue,
+    Unknown,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::InvalidMode => write!(f, "Invalid mode."),
+            ParseErrorKind::NoModeDeclared => write!(f, "No mode declared."),
+            ParseErrorKind::InvalidSound => write!(f, "Invalid sound format."),
+            ParseErrorKind::ModeNotHit => write!(f,
+                "Not all modes were described."),
+            ParseErrorKind::KeyWithoutValue => write!(f,
+                "Key given without value."),
+            ParseErrorKind::InvalidValue => write!(f,
+                "An invalid value was encountered."),
+            ParseErrorKind::InvalidNoteOrder(bar) => write!(f,
+                "Note in bar {} starts before the previous note.", bar),
+            ParseErrorKind::InvalidBeat(bar) => write!(f,
+                "Bar {} has no such beat.", bar),
+            ParseErrorKind::PastEnd(bar) => write!(f,
+                "Bar {} is past the end of the timeline.", bar),
+            ParseErrorKind::InvalidLength(bar) => write!(f,
+                "Note in bar {} has an invalid length.", bar),
+            ParseErrorKind::InvalidKey => write!(f, "Key is invalid."),
+            ParseErrorKind::UnknownInstrument => write!(f,
+                "No instrument has this name."),
+            ParseErrorKind::ExtraValue => write!(f,
+                "This value and any after it are ignored."),
+            ParseErrorKind::Unknown => write!(f, "Unknown error."),
+        }
+    }
+}
+
+/// The part of a line an error points at
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    /// Line number, counting from 1
+    pub line: usize,
+    /// Column of the first character, counting from 1
+    pub column: usize,
+    /// Number of characters pointed at
+    pub width: usize,
+    /// The text pointed at, empty if the error is about something missing
+    pub token: String,
+    /// The whole line
+    pub text: String,
+}
 
 #[derive(Debug)]
-pub enum ParseError {
-    InvalidMode(String, usize),
-    NoModeDeclared(String, usize),
-    InvalidSound(String, usize),
-    ModeNotHit(String),
-    KeyWithoutValue(String, usize),
-    InvalidValue(String, usize),
-    InvalidNoteOrder(String, usize),
-    InvalidKey(String, usize),
-    Unknown(String, usize),
+pub enum ThrorganError {
+    /// A problem in the text of a file. `span` is `None` for problems with
+    /// the file as a whole.
+    Parse {
+        file: String,
+        kind: ParseErrorKind,
+        span: Option<Box<Span>>,
+        cause: Option<Cause>,
+    },
+    /// A file could not be read or written. `path` is `None` when writing to
+    /// something other than a file.
+    Io { path: Option<String>, cause: io::Error },
+    FileAlreadyExists { path: String },
+    CannotWrite { path: String, cause: io::Error },
+    /// The options cannot be used together or are not supported.
+    InvalidOption(String),
+    /// The song has errors. Every problem found is listed, warnings
+    /// included.
+    Diagnostics(Diagnostics),
 }
 
+impl ThrorganError {
+    /// Make an error for a problem with `file` as a whole.
+    pub fn file(file: &str, kind: ParseErrorKind) -> ThrorganError {
+        ThrorganError::Parse { file: file.to_string(), kind, span: None,
+            cause: None }
+    }
+
+    /// Make an error for a file that could not be read.
+    pub fn read(path: &str, cause: io::Error) -> ThrorganError {
+        ThrorganError::Io { path: Some(path.to_string()), cause }
+    }
+}
+
+impl From<io::Error> for ThrorganError {
+    fn from(cause: io::Error) -> ThrorganError {
+        ThrorganError::Io { path: None, cause }
+    }
+}
 
-impl fmt::Display for ParseError {
+/// Write `span` of a line of `file` underlined with carets.
+fn write_snippet(f: &mut fmt::Formatter, file: &str, span: &Span)
+-> fmt::Result {
+    let gutter = " ".repeat(span.line.to_string().len());
+    writeln!(f, "{}--> {}:{}:{}", gutter, file, span.line, span.column)?;
+    writeln!(f, "{} |", gutter)?;
+    writeln!(f, "{} | {}", span.line, span.text)?;
+    // Keep tabs so that the carets line up with the text above
+    let indent: String = span.text.chars().take(span.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' }).collect();
+    write!(f, "{} | {}{}", gutter, indent, "^".repeat(span.width))
+}
+
+impl fmt::Display for ThrorganError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            ParseError::InvalidMode(name, num) =>  write!(f,
-                "Instrument {} line {}: Invalid mode.", name, num),
-            ParseError::NoModeDeclared(name, num) =>  write!(f,
-                "Instrument {} line {}: No mode declared.", name, num),
-            ParseError::InvalidSound(name, num) =>  write!(f,
-                "Instrument {} line {}: Invalid sound format.", name, num),
-            ParseError::ModeNotHit(name) =>  write!(f,
-                "File {}: Not all modes were described.", name),
-            ParseError::KeyWithoutValue(name, num) =>  write!(f,
-                "Instrument {} line {}: Key given without value.", name, num),
-            ParseError::InvalidValue(name, num) =>  write!(f,
-                "Instrument {} line {}: An invalid value was encountered.",
-                name, num),
-            ParseError::InvalidNoteOrder(name, num) =>  write!(f,
-                "Instrument {} line {}: Invalid note order.",
-                name, num),
-            ParseError::InvalidKey(name, num) =>  write!(f,
-                "Instrument {} line {}: Key is invalid.", name, num),
-            ParseError::Unknown(name, num) =>  write!(f,
-                "Instrument {} line {}: Unknown error.", name, num),
-            // TO DO: Implement file and line numbers.
-        }
-    }
-}
-
-impl error::Error for ParseError {
+            ThrorganError::Parse { file, kind, span: Some(span), .. } => {
+                writeln!(f, "{}", kind)?;
+                write_snippet(f, file, span)
+            },
+            ThrorganError::Parse { file, kind, span: None, .. } => write!(f,
+                "{}\n --> {}", kind, file),
+            ThrorganError::Io { path: Some(path), .. } => write!(f,
+                "The file {} could not be read.", path),
+            ThrorganError::Io { path: None, cause } => write!(f, "{}", cause),
+            ThrorganError::FileAlreadyExists { path } => write!(f,
+                "The file {} already exists.", path),
+            ThrorganError::CannotWrite { path, .. } => write!(f,
+                "The file {} could not be written.", path),
+            ThrorganError::InvalidOption(message) => write!(f, "{}", message),
+            ThrorganError::Diagnostics(diagnostics) => write!(f, "{}",
+                diagnostics),
+        }
+    }
+}
+
+impl error::Error for ThrorganError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match *self {
-            _ => None
+        match self {
+            ThrorganError::Parse { cause: Some(cause), .. } =>
+                Some(cause.as_ref()),
+            ThrorganError::Io { path: Some(_), cause } |
+            ThrorganError::CannotWrite { cause, .. } => Some(cause),
+            _ => None,
+        }
+    }
+}
+
+/// How serious a problem found in a file is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// Something that is probably a mistake but does not stop compiling
+    Warning,
+    Error,
+}
+
+/// Collects the problems found while reading a song and its instruments, so
+/// that reading can go on past a bad line and every problem can be reported
+/// at once.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    problems: Vec<(Severity, ThrorganError)>, // In the order they were found
+}
+
+impl Diagnostics {
+    pub fn new() -> Diagnostics {
+        Diagnostics { problems: Vec::new() }
+    }
+
+    /// Record an error.
+    pub fn error(&mut self, error: ThrorganError) {
+        self.problems.push((Severity::Error, error));
+    }
+
+    /// Record a warning.
+    pub fn warn(&mut self, warning: ThrorganError) {
+        self.problems.push((Severity::Warning, warning));
+    }
+
+    /// Get the value of `result`, or record its error and get `None`.
+    pub fn check<T>(&mut self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.error(e);
+                None
+            },
         }
     }
-}
\ No newline at end of file
+
+    /// Warn if `item` is not `None`, since it is a value at the end of `line`
+    /// that will not be read.
+    pub fn unused(&mut self, line: &Line, item: Option<&str>) {
+        if let Some(item) = item {
+            self.warn(line.error(ParseErrorKind::ExtraValue, item));
+        }
+    }
+
+    /// Get every problem in the order they were found.
+    pub fn problems(&self) -> &[(Severity, ThrorganError)] {
+        &self.problems
+    }
+
+    /// Count the problems of severity `severity`.
+    pub fn count(&self, severity: Severity) -> usize {
+        self.problems.iter().filter(|(s, _)| *s == severity).count()
+    }
+
+    /// Fail with every problem found if any of them is an error, or get the
+    /// warnings otherwise.
+    pub fn into_result(self) -> Result<Diagnostics> {
+        if self.count(Severity::Error) > 0 {
+            return Err(ThrorganError::Diagnostics(self));
+        }
+        Ok(self)
+    }
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (severity, problem)) in self.problems.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n\n")?;
+            }
+            match severity {
+                Severity::Warning => write!(f, "warning: {}", problem)?,
+                Severity::Error => write!(f, "error: {}", problem)?,
+            }
+            let mut cause = error::Error::source(problem);
+            while let Some(e) = cause {
+                write!(f, "\ncaused by: {}", e)?;
+                cause = e.source();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A line of a file being parsed, used to make errors pointing into it.
+#[derive(Clone, Copy)]
+pub struct Line<'a> {
+    file: &'a str,
+    num: usize, // Counting from 0
+    text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    /// Make the line `text`, which is line `num` of `file` counting from 0.
+    pub fn new(file: &'a str, num: usize, text: &'a str) -> Line<'a> {
+        Line { file, num, text }
+    }
+
+    /// Get the text of the line.
+    pub fn text(&self) -> &'a str {
+        self.text
+    }
+
+    /// Get the span of `token`, which should be a slice of the line. Other
+    /// tokens are looked for in the line, and point at the end of the line if
+    /// they are not found.
+    fn span(&self, token: &str) -> Span {
+        let start = self.text.as_ptr() as usize;
+        let offset = match (token.as_ptr() as usize).checked_sub(start) {
+            Some(offset) if offset + token.len() <= self.text.len() => offset,
+            _ => self.text.find(token).filter(|_| !token.is_empty())
+                .unwrap_or(self.text.len()),
+        };
+        Span { line: self.num + 1,
+            column: self.text[..offset].chars().count() + 1,
+            width: token.chars().count().max(1), token: token.to_string(),
+            text: self.text.to_string() }
+    }
+
+    /// Make an error of `kind` pointing at `token`.
+    pub fn error(&self, kind: ParseErrorKind, token: &str) -> ThrorganError {
+        ThrorganError::Parse { file: self.file.to_string(), kind,
+            span: Some(Box::new(self.span(token))), cause: None }
+    }
+
+    /// Make an error of `kind` pointing at `token` which was caused by
+    /// `cause`.
+    pub fn caused_by<E>(&self, kind: ParseErrorKind, token: &str, cause: E)
+    -> ThrorganError where E: Into<Cause> {
+        ThrorganError::Parse { file: self.file.to_string(), kind,
+            span: Some(Box::new(self.span(token))),
+            cause: Some(cause.into()) }
+    }
+
+    /// Make an error of `kind` for a value missing from the end of the line.
+    pub fn missing(&self, kind: ParseErrorKind) -> ThrorganError {
+        self.error(kind, &self.text[self.text.len()..])
+    }
+
+    /// Get `item`, the next value of the line, or an error of `kind` if the
+    /// line has ended.
+    pub fn expect<'b>(&self, item: Option<&'b str>, kind: ParseErrorKind)
+    -> Result<&'b str> {
+        item.ok_or_else(|| self.missing(kind))
+    }
+
+    /// Parse `token`, giving an error of `kind` pointing at it if it is not
+    /// valid.
+    pub fn parse<T>(&self, token: &str, kind: ParseErrorKind) -> Result<T>
+    where T: FromStr, T::Err: error::Error + Send + Sync + 'static {
+        token.parse::<T>().map_err(|e| self.caused_by(kind, token, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet() {
+        let text = "0 A4 0 1 x";
+        let line = Line::new("song.thr", 6, text);
+        let e = line.parse::<f32>(&text[9..], ParseErrorKind::InvalidValue)
+            .unwrap_err();
+        assert_eq!(e.to_string(), "An invalid value was encountered.\n \
+            --> song.thr:7:10\n  |\n7 | 0 A4 0 1 x\n  |          ^");
+        assert!(error::Error::source(&e).is_some());
+
+        let e = line.missing(ParseErrorKind::KeyWithoutValue);
+        match e {
+            ThrorganError::Parse { span: Some(span), .. } =>
+                assert_eq!((span.column, span.width), (11, 1)),
+            _ => panic!("Expected a parse error"),
+        }
+    }
+}
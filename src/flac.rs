@@ -0,0 +1,432 @@
+//! # FLAC
+//!
+//! This file holds a lossless FLAC encoder. Each block of samples is stored
+//! with whichever fixed polynomial predictor leaves the smallest residual, and
+//! the residual is Rice coded over the best partitioning of the block. LPC
+//! predictors and inter-channel decorrelation are not used, which costs a
+//! little compression but keeps the encoder short.
+
+use crate::encode::{Encoder, Samples, StreamInfo};
+use crate::options::SampleFormat;
+use std::io::{self, Seek, SeekFrom, Write};
+
+const BLOCK_SIZE: usize = 4096; // Samples per channel in each frame
+const MAX_ORDER: usize = 4; // Highest fixed predictor order
+const MAX_PARTITION_ORDER: u32 = 8;
+const MAX_RICE_PARAMETER: u32 = 14; // Largest parameter with 4-bit coding
+const STREAMINFO_OFFSET: u64 = 8; // Start of the STREAMINFO block body
+
+/// Collects a stream of bits, most significant first.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32, // Bits used in the last byte, from 0 to 7
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), bits: 0 }
+    }
+
+    /// Write the lowest `count` bits of `value`.
+    fn write(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            if self.bits == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits);
+            self.bits = (self.bits + 1) % 8;
+        }
+    }
+
+    /// Write `value` as a two's complement number of `count` bits.
+    fn write_signed(&mut self, value: i64, count: u32) {
+        self.write(value as u64 & ((1 << count) - 1), count);
+    }
+
+    /// Write `count` zeros followed by a one.
+    fn write_unary(&mut self, count: u64) {
+        for _ in 0..count / 32 {
+            self.write(0, 32);
+        }
+        self.write(1, (count % 32) as u32 + 1);
+    }
+
+    /// Pad the last byte with zeros.
+    fn align(&mut self) {
+        self.bits = 0;
+    }
+}
+
+/// Get the CRC-8 of `bytes` with polynomial x^8 + x^2 + x + 1, used for frame
+/// headers.
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Get the CRC-16 of `bytes` with polynomial x^16 + x^15 + x^2 + 1, used for
+/// whole frames.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else {
+                crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Get the residual of the fixed predictor of `order` over `samples`, which
+/// starts after the first `order` warm-up samples.
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let mut residual = samples.to_vec();
+    for _ in 0..order {
+        residual = residual.windows(2).map(|w| w[1] - w[0]).collect();
+    }
+    residual
+}
+
+/// Zigzag a signed residual so that small magnitudes get small codes.
+fn fold(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Choose a Rice parameter for `residual` from its mean and get the number of
+/// bits it takes.
+fn rice_cost(residual: &[i64]) -> (u32, u64) {
+    let sum: u64 = residual.iter().map(|&r| fold(r)).sum();
+    let mean = sum / residual.len().max(1) as u64;
+    let k = u32::min(mean.checked_ilog2().unwrap_or(0), MAX_RICE_PARAMETER);
+    let bits = residual.iter().map(|&r| (fold(r) >> k) + 1 + k as u64)
+        .sum::<u64>();
+    (k, bits)
+}
+
+/// Plan the Rice coding of `residual` for a block of `block_size` samples
+/// with a predictor of `order`. Returns the partition order, the parameter of
+/// each partition and the total number of bits.
+fn plan_residual(residual: &[i64], block_size: usize, order: usize)
+-> (u32, Vec<u32>, u64) {
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1 << partition_order;
+        let length = block_size >> partition_order;
+        if !block_size.is_multiple_of(partitions) || length <= order {
+            break;
+        }
+        let mut parameters = Vec::new();
+        let mut bits = 6; // Coding method and partition order
+        let mut start = 0;
+        for p in 0..partitions {
+            let end = if p == 0 { length - order } else { start + length };
+            let (k, cost) = rice_cost(&residual[start..end]);
+            parameters.push(k);
+            bits += 4 + cost;
+            start = end;
+        }
+        if best.as_ref().is_none_or(|b| bits < b.2) {
+            best = Some((partition_order, parameters, bits));
+        }
+    }
+    best.unwrap()
+}
+
+/// Write one channel of a frame as a subframe, choosing between a constant,
+/// a fixed predictor and the verbatim samples.
+fn write_subframe(out: &mut BitWriter, samples: &[i64], bits: u32) {
+    if samples.iter().all(|&s| s == samples[0]) {
+        out.write(0b0000_0000, 8);
+        out.write_signed(samples[0], bits);
+        return;
+    }
+
+    let verbatim = samples.len() as u64 * bits as u64;
+    let best = (0..=MAX_ORDER.min(samples.len() - 1)).map(|order| {
+        let residual = fixed_residual(samples, order);
+        let plan = plan_residual(&residual, samples.len(), order);
+        let cost = plan.2 + (order as u64 * bits as u64);
+        (order, residual, plan, cost)
+    }).min_by_key(|candidate| candidate.3).unwrap();
+    let (order, residual, (partition_order, parameters, _), cost) = best;
+
+    if cost >= verbatim {
+        out.write(0b0000_0010, 8);
+        for &s in samples {
+            out.write_signed(s, bits);
+        }
+        return;
+    }
+
+    out.write(0b0001_0000 | (order as u64) << 1, 8);
+    for &s in &samples[..order] {
+        out.write_signed(s, bits);
+    }
+    out.write(0b00, 2); // Rice coding with 4-bit parameters
+    out.write(partition_order as u64, 4);
+    let length = samples.len() >> partition_order;
+    let mut start = 0;
+    for (p, &k) in parameters.iter().enumerate() {
+        let end = if p == 0 { length - order } else { start + length };
+        out.write(k as u64, 4);
+        for &r in &residual[start..end] {
+            let folded = fold(r);
+            out.write_unary(folded >> k);
+            out.write(folded, k);
+        }
+        start = end;
+    }
+}
+
+/// Get the frame header code for `sample_rate` and the number of bits of the
+/// value following the header, if the code needs one.
+fn sample_rate_code(sample_rate: u32) -> (u64, u64, u32) {
+    match sample_rate {
+        88_200 => (0b0001, 0, 0),
+        176_400 => (0b0010, 0, 0),
+        192_000 => (0b0011, 0, 0),
+        8_000 => (0b0100, 0, 0),
+        16_000 => (0b0101, 0, 0),
+        22_050 => (0b0110, 0, 0),
+        24_000 => (0b0111, 0, 0),
+        32_000 => (0b1000, 0, 0),
+        44_100 => (0b1001, 0, 0),
+        48_000 => (0b1010, 0, 0),
+        96_000 => (0b1011, 0, 0),
+        r if r % 1000 == 0 && r / 1000 < 256 => (0b1100, r as u64 / 1000, 8),
+        r if r < 65_536 => (0b1101, r as u64, 16),
+        r if r % 10 == 0 && r / 10 < 65_536 => (0b1110, r as u64 / 10, 16),
+        _ => (0b0000, 0, 0), // Read from STREAMINFO
+    }
+}
+
+/// Write `value` in the extended UTF-8 coding FLAC uses for frame numbers.
+fn write_utf8(out: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        out.write(value, 8);
+        return;
+    }
+    let mut continuation = Vec::new();
+    let mut rest = value;
+    let mut lead_bits = 6; // Payload bits left in the first byte
+    while rest >= 1 << lead_bits {
+        continuation.push(0x80 | (rest & 0x3F));
+        rest >>= 6;
+        lead_bits -= 1;
+    }
+    let count = continuation.len() as u32 + 1;
+    let mark = (0xFF00u64 >> count) & 0xFF;
+    out.write(mark | rest, 8);
+    for byte in continuation.iter().rev() {
+        out.write(*byte, 8);
+    }
+}
+
+/// A FLAC file holding integer samples. The STREAMINFO block is written first
+/// and patched with the length and frame sizes once they are known. The MD5
+/// signature of the audio is left unset, which decoders accept.
+pub struct Flac<W: Write + Seek> {
+    out: W,
+    info: StreamInfo,
+    pending: Vec<Vec<i64>>, // Samples of each channel not yet in a frame
+    frame_number: u64,
+    frames: u64, // Samples written to each channel so far
+    frame_sizes: (u32, u32), // Smallest and largest frame in bytes
+}
+
+impl<W: Write + Seek> Flac<W> {
+    pub fn new(out: W, info: StreamInfo) -> io::Result<Flac<W>> {
+        if info.format == SampleFormat::Float32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "FLAC cannot store float samples"));
+        }
+        let mut flac = Flac { out, info, pending: vec![Vec::new();
+            info.channels as usize], frame_number: 0, frames: 0,
+            frame_sizes: (u32::MAX, 0) };
+        flac.out.write_all(b"fLaC")?;
+        flac.out.write_all(&[0x80, 0, 0, 34])?; // Last block, STREAMINFO
+        flac.write_streaminfo()?;
+        Ok(flac)
+    }
+
+    /// Write the body of the STREAMINFO block for the samples written so far.
+    fn write_streaminfo(&mut self) -> io::Result<()> {
+        let mut block = BitWriter::new();
+        block.write(BLOCK_SIZE as u64, 16); // Smallest block size
+        block.write(BLOCK_SIZE as u64, 16); // Largest block size
+        let (min_frame, max_frame) = match self.frame_sizes {
+            (u32::MAX, _) => (0, 0), // Unknown
+            sizes => sizes,
+        };
+        block.write(min_frame as u64, 24);
+        block.write(max_frame as u64, 24);
+        block.write(self.info.sample_rate as u64, 20);
+        block.write(self.info.channels as u64 - 1, 3);
+        block.write(self.info.bits() as u64 - 1, 5);
+        block.write(self.frames, 36);
+        block.write(0, 64); // MD5 signature, unset
+        block.write(0, 64);
+        self.out.write_all(&block.bytes)
+    }
+
+    /// Encode the first `len` pending samples of each channel as one frame.
+    fn write_frame(&mut self, len: usize) -> io::Result<()> {
+        let mut frame = BitWriter::new();
+        frame.write(0b1111_1111_1111_1000, 16); // Sync code, fixed blocks
+        let size_code = if len == BLOCK_SIZE { 0b1100 } else { 0b0111 };
+        frame.write(size_code, 4);
+        let (rate_code, rate, rate_bits) = sample_rate_code(
+            self.info.sample_rate);
+        frame.write(rate_code, 4);
+        frame.write(self.info.channels as u64 - 1, 4); // Independent channels
+        let bits = self.info.bits() as u32;
+        frame.write(if bits == 16 { 0b100 } else { 0b110 }, 3);
+        frame.write(0, 1);
+        write_utf8(&mut frame, self.frame_number);
+        if len != BLOCK_SIZE {
+            frame.write(len as u64 - 1, 16);
+        }
+        frame.write(rate, rate_bits);
+        let crc = crc8(&frame.bytes);
+        frame.write(crc as u64, 8);
+
+        for channel in self.pending.iter_mut() {
+            let samples: Vec<i64> = channel.drain(..len).collect();
+            write_subframe(&mut frame, &samples, bits);
+        }
+        frame.align();
+        let crc = crc16(&frame.bytes);
+        frame.write(crc as u64, 16);
+
+        self.out.write_all(&frame.bytes)?;
+        let size = frame.bytes.len() as u32;
+        self.frame_sizes = (self.frame_sizes.0.min(size),
+            self.frame_sizes.1.max(size));
+        self.frame_number += 1;
+        self.frames += len as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Encoder for Flac<W> {
+    fn write(&mut self, samples: &Samples) -> io::Result<()> {
+        let samples = match samples {
+            Samples::Int(samples) => samples,
+            Samples::Float(_) => return Err(io::Error::new(
+                io::ErrorKind::InvalidInput, "FLAC cannot store float samples")),
+        };
+        let channels = self.pending.len();
+        for frame in samples.chunks_exact(channels) {
+            for (channel, &sample) in self.pending.iter_mut().zip(frame) {
+                channel.push(sample as i64);
+            }
+        }
+        while self.pending[0].len() >= BLOCK_SIZE {
+            self.write_frame(BLOCK_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let len = self.pending[0].len();
+        if len > 0 {
+            self.write_frame(len)?;
+        }
+        self.out.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+        self.write_streaminfo()?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::TAU;
+    use std::io::Cursor;
+
+    /// Make `frames` samples of each of `channels` channels at `bits`: a sum
+    /// of sines with some noise, a silent stretch and a square wave at full
+    /// scale, to try every kind of subframe.
+    fn test_signal(frames: usize, channels: u16, bits: u16) -> Vec<i32> {
+        let peak = (1i64 << (bits - 1)) - 1;
+        let mut seed = 12345u32;
+        let mut samples = Vec::new();
+        for i in 0..frames {
+            for c in 0..channels as usize {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (seed >> 16) as f64 / 65536.0 - 0.5;
+                let t = i as f64 / 44_100.0;
+                let value = match i * 4 / frames {
+                    0 | 1 => 0.5 * (t * 440.0 * (c + 1) as f64 * TAU).sin() +
+                        0.2 * (t * 1234.5 * TAU).sin() + 0.05 * noise,
+                    2 => 0.0,
+                    _ => if (i / 50) % 2 == 0 { 1.0 } else { -1.0 },
+                };
+                samples.push((value * peak as f64).round() as i32);
+            }
+        }
+        samples
+    }
+
+    /// Encode `samples` in pieces of uneven length and decode them again.
+    fn round_trip(samples: &[i32], channels: u16, format: SampleFormat) {
+        let info = StreamInfo { sample_rate: 44_100, channels, format };
+        let mut bytes = Vec::new();
+        {
+            let mut flac = Flac::new(Cursor::new(&mut bytes), info).unwrap();
+            for piece in samples.chunks(1000 * channels as usize) {
+                flac.write(&Samples::Int(piece.to_vec())).unwrap();
+            }
+            flac.finish().unwrap();
+        }
+
+        let mut reader = claxon::FlacReader::new(Cursor::new(bytes)).unwrap();
+        let stream = reader.streaminfo();
+        assert_eq!(stream.sample_rate, 44_100);
+        assert_eq!(stream.channels, channels as u32);
+        assert_eq!(stream.bits_per_sample, info.bits() as u32);
+        assert_eq!(stream.samples, Some((samples.len() / channels as usize)
+            as u64));
+        let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap())
+            .collect();
+        assert!(decoded == samples, "decoded samples differ");
+    }
+
+    #[test]
+    fn decodes_to_input() {
+        // Not a whole number of blocks, so the last frame is shorter
+        let frames = 3 * BLOCK_SIZE + 1234;
+        for &channels in &[1, 2] {
+            for &(format, bits) in &[(SampleFormat::Int16, 16),
+                (SampleFormat::Int24, 24)] {
+                round_trip(&test_signal(frames, channels, bits), channels,
+                    format);
+            }
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        // Check values of the CRC-8 and CRC-16 (BUYPASS) used by FLAC
+        assert_eq!(crc8(b"123456789"), 0xF4);
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn frame_numbers() {
+        let mut out = BitWriter::new();
+        write_utf8(&mut out, 0x7F);
+        write_utf8(&mut out, 0x80);
+        write_utf8(&mut out, 0x800);
+        assert_eq!(out.bytes, [0x7F, 0xC2, 0x80, 0xE0, 0xA0, 0x80]);
+    }
+}
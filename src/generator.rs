@@ -1,108 +1,258 @@
-use crate::instrument::{Instrument, Sound, Damp};
-use std::rc::Rc;
-use std::borrow::Borrow;
-use crate::output::{Output, FREQ_SIZE, SAMPLE_RATE};
-use rustfft::num_complex::Complex;
-
-const MEASURE_LENGTH: usize = 4;
-const BD_SIZE: usize = 2 * MEASURE_LENGTH;
-const MIN_FREQ: f32 = 10.0;
-const MAX_FREQ: f32 = 10000.0;
-
-fn frequency_to_index(freq: f32) -> usize {
-    (FREQ_SIZE as f32 * (f32::ln(freq) - f32::ln(MIN_FREQ)) /
-        (f32::ln(MAX_FREQ) - f32::ln(MIN_FREQ))) as usize
+use crate::instrument::{Instrument, Sound, Damp, Envelope};
+use crate::errors::Result;
+use crate::options::Options;
+use crate::render::{Render, new_renderer};
+use crate::timeline::TempoMap;
+use crate::output::Output;
+use std::f32::consts::FRAC_PI_4;
+
+const CHUNK_SIZE: usize = 4096; // Samples rendered at a time
+
+enum Note {
+    SteadyState(Vec<Sound>, u64, u64, Envelope), // First and last sample of
+                                                 // the note
+    End(Vec<Sound>, u64, u32, f32), // First sample, length and starting
+                                    // volume of the release
 }
-fn index_to_frequency(index: usize) -> f32 {
-    MIN_FREQ * f32::powf(MAX_FREQ / MIN_FREQ, index as f32 / FREQ_SIZE as f32)
+
+impl Note {
+    /// Get the first sample of the note and the sample after its last.
+    fn span(&self) -> (u64, u64) {
+        match *self {
+            Note::SteadyState(_, begin, end, _) => (begin, end),
+            Note::End(_, begin, dur, _) => (begin,
+                begin.saturating_add(dur as u64)),
+        }
+    }
 }
 
-enum Note {
-    SteadyState(Vec<Sound>),
-    End(Vec<Sound>, f32),
+/// Get the gain of each of `channels` output channels for a note at `pan`,
+/// from -1 (left) to 1 (right). Stereo uses equal-power panning, so a note
+/// keeps the same loudness wherever it is placed.
+fn pan_gains(pan: f32, channels: usize) -> Vec<f32> {
+    if channels == 1 {
+        return vec![1.0];
+    }
+    let angle = (pan + 1.0) * FRAC_PI_4;
+    vec![angle.cos(), angle.sin()]
 }
 
-/// Record frequency data on each time before pushed into a wav file
-pub struct Breakdown {
-    low_time: u32, // Minimum time in breakdown segment
-    notes: [Vec<Rc<Note>>; BD_SIZE], // List of notes
-    active: usize, // Location of minimum time in breakdown segment
-    tempo: u32,
-    output: Output,
+/// Record frequency data on each time before pushed into the output. Notes
+/// are kept until the chunk containing their last sample has been pushed, so
+/// they can be as long as needed.
+pub struct Breakdown<'a> {
+    notes: Vec<(Note, Vec<f32>)>, // Notes that have not finished sounding and
+                                  // their gain in each channel
+    tempo_map: TempoMap,
+    output: Output<'a>,
     damp: Damp,
+    renderers: Vec<Box<dyn Render>>, // One for each channel
+    sample_rate: u32,
+    sample: u64, // Number of samples pushed so far
 }
 
-impl Breakdown {
-    pub fn new(tempo: u32, output_file:&str) -> Breakdown {
-        const INIT: Vec<Rc<Note>> = Vec::new();
-        Breakdown {low_time: 0, notes: [INIT; BD_SIZE], active: 0, tempo,
-            output: Output::new(output_file), damp: Damp::new() }
+impl<'a> Breakdown<'a> {
+    pub fn new(tempo_map: TempoMap, output: Output<'a>, options: &Options)
+    -> Breakdown<'a> {
+        Breakdown {notes: Vec::new(), tempo_map, output, damp: Damp::new(),
+            renderers: (0..options.channels).map(|_| new_renderer(
+                options.renderer, options.sample_rate)).collect(),
+            sample_rate: options.sample_rate, sample: 0 }
     }
 
-    pub fn add_note(&mut self, inst: &Instrument, note: u32, time: u32,
-        note_length: u32, vol: f32) {
-        let duration = get_duration_from_length(note_length);
-        let freq = 440.0 * f32::powf(2.0, (note - 69) as f32 /12.0);
-        let steady_note = Rc::new(Note::SteadyState(
-            inst.generate_steady_state(freq, vol)));
-        let begin = time - self.low_time;
-        for i in begin..(begin + duration) {
-            let index = (i as usize + self.active) % BD_SIZE;
-            self.notes[index].push(Rc::clone(&steady_note));
+    /// Add a note of frequency `freq` starting at tick `time` and lasting
+    /// `note_length` ticks, placed at `pan` between the left and right
+    /// channels. Notes must be added in order of their starting time.
+    pub fn add_note(&mut self, inst: &Instrument, freq: f32, time: u64,
+        note_length: u64, vol: f32, pan: f32) -> Result<()> {
+        let begin = self.tempo_map.tick_to_sample(time);
+        while self.sample + CHUNK_SIZE as u64 <= begin {
+            self.push(CHUNK_SIZE)?;
         }
-        // TO DO: implement end of notes
 
-        for _ in 0..begin {
-            self.push();
-        }
+        let end = self.tempo_map.tick_to_sample(time.saturating_add(
+            note_length));
+        let gains = pan_gains(pan, self.renderers.len());
+        self.notes.push((Note::SteadyState(inst.generate_steady_state(freq,
+            vol), begin, end, inst.envelope()), gains.clone()));
+
+        // The release rings on after the note, starting wherever the note
+        // ends inside its last chunk.
+        let release = (inst.reverb() * self.sample_rate as f32) as u32;
+        let release_level = inst.envelope().level(
+            (end - begin) as f32 / self.sample_rate as f32);
+        self.notes.push((Note::End(inst.generate_steady_state(freq, vol), end,
+            release, release_level), gains));
+        Ok(())
     }
 
-    pub fn push_all(&mut self) {
-        for _ in 0..BD_SIZE {
-            self.push();
+    /// Push chunks until every note has finished and finish the output file.
+    /// The output ends on the last sample of the last note.
+    pub fn push_all(&mut self) -> Result<()> {
+        while let Some(end) = self.notes.iter().map(|(n, _)| n.span().1)
+            .max() {
+            let remaining = (end - self.sample) as usize;
+            self.push(usize::min(CHUNK_SIZE, remaining))?;
         }
+        self.output.finalize()?;
+        Ok(())
     }
 
-    fn push(&mut self) {
-        for note in self.notes[self.active].iter() {
-            let chunk_dur = self.tempo as f32 / 60.0 / (MEASURE_LENGTH as f32);
-            let time_size = (SAMPLE_RATE as f32 * chunk_dur) as usize;
-            let mut cqt = vec![[Complex{re:0.0, im:0.0}; FREQ_SIZE]; time_size];
-
-            let frequencies: Vec<f32> = (0..FREQ_SIZE).map(
-                |x| index_to_frequency(x)).collect();
-
-            for t in 0..time_size {
-                match note.borrow() {
-                    Note::SteadyState(sounds) => {
-                        for sound in sounds.iter(){
-                            for w in 0..FREQ_SIZE {
-                                cqt[t][w] += sound.get_power(
-                                    frequencies[w]);
-                            }
-                        }
-                    }
-                    Note::End(sounds, dur) => {
-                        for sound in sounds.iter(){
-                            for w in 0..FREQ_SIZE {
-                                cqt[t][w] += sound.get_power(frequencies[w]) * 
-                                    self.damp.end_damp(
-                                        t as u32, 
-                                        (dur * SAMPLE_RATE as f32) as u32);
-                            }
-                        }
-                    }
+    /// Render every note sounding in the next `time_size` samples and send
+    /// the result to the output.
+    fn push(&mut self, time_size: usize) -> Result<()> {
+        let chunk_end = self.sample + time_size as u64;
+        for renderer in self.renderers.iter_mut() {
+            renderer.begin(self.sample, time_size);
+        }
+
+        let damp = &mut self.damp;
+        let sample_rate = self.sample_rate as f32;
+        for (note, gains) in self.notes.iter() {
+            let (begin, end) = note.span();
+            if begin >= chunk_end || end <= self.sample {
+                continue;
+            }
+            let (sounds, envelope): (_, Vec<f32>) = match note {
+                Note::SteadyState(sounds, begin, end, envelope) => {
+                    (sounds, (self.sample..self.sample + time_size as u64)
+                    .map(|s| if s < *begin || s >= *end {
+                        0.0
+                    } else {
+                        envelope.level((s - begin) as f32 / sample_rate)
+                    }).collect())
+                },
+                Note::End(sounds, begin, dur, level) => {
+                    (sounds, (self.sample..self.sample + time_size as u64)
+                    .map(|s| if s < *begin {
+                        0.0
+                    } else {
+                        level * damp.end_damp((s - begin) as u32, *dur)
+                    }).collect())
+                },
+            };
+            for (renderer, &gain) in self.renderers.iter_mut().zip(gains) {
+                if gain > 0.0 {
+                    let envelope: Vec<f32> = envelope.iter()
+                        .map(|level| gain * level).collect();
+                    renderer.add(sounds, &envelope);
                 }
+            }
+        }
+
+        let channels: Vec<Vec<f32>> = self.renderers.iter_mut()
+            .map(|renderer| renderer.finish()).collect();
+        self.output.push(&channels)?;
+        self.notes.retain(|(note, _)| note.span().1 > chunk_end);
+        self.sample = chunk_end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::{Collect, Encoder};
+    use crate::errors::Diagnostics;
+    use crate::instrument::create_instrument;
+    use crate::options::{Normalization, Renderer, SampleFormat};
+    use crate::timeline::TICKS_PER_BEAT;
+    use std::{env, fs, process};
+
+    /// Render one note at 120 BPM and get the number of samples written.
+    fn render_length(note_length: u64) -> u64 {
+        // Tests of other processes may render the same length at once
+        let path = env::temp_dir().join(format!("throrgan-{}-length-{}.wav",
+            process::id(), note_length));
+        let path = path.to_str().unwrap();
+        let inst = Instrument::new("sine", 1.0, &mut Diagnostics::new())
+            .unwrap();
+        let options = Options { renderer: Renderer::Oscillator,
+            ..Options::default() };
+        let output = Output::new(path, &options).unwrap();
+        let mut bd = Breakdown::new(TempoMap::new(120.0, 44_100), output,
+            &options);
+        bd.add_note(&inst, 440.0, 0, note_length, 1.0, 0.0).unwrap();
+        bd.push_all().unwrap();
+        let size = fs::metadata(path).unwrap().len();
+        fs::remove_file(path).unwrap();
+        (size - 44) / 2
+    }
+
+    #[test]
+    fn quarter_note_length() {
+        // The prefab sine rings for half a second after the note ends.
+        let release = 22_050;
+        assert_eq!(render_length(TICKS_PER_BEAT), 22_050 + release);
+        assert_eq!(render_length(TICKS_PER_BEAT / 3), 7_350 + release);
+        assert_eq!(render_length(4 * TICKS_PER_BEAT), 88_200 + release);
+    }
+
+    /// Render a note of `inst` a beat long at 120 BPM and 490 Hz, whose cosine
+    /// peaks on every 90th sample, and get the samples written.
+    fn render(inst: &Instrument, pan: f32, options: &Options) -> Vec<f32> {
+        let options = Options { renderer: Renderer::Oscillator,
+            sample_format: SampleFormat::Float32, ..options.clone() };
+        let mut samples = Vec::new();
+        let output = Output::with_encoder(|_| Ok(Box::new(Collect {
+            samples: &mut samples }) as Box<dyn Encoder>), &options).unwrap();
+        let mut bd = Breakdown::new(TempoMap::new(120.0, 44_100), output,
+            &options);
+        bd.add_note(inst, 490.0, 0, TICKS_PER_BEAT, 1.0, pan).unwrap();
+        bd.push_all().unwrap();
+        drop(bd);
+        samples
+    }
+
+    #[test]
+    fn envelope_shape() {
+        let inst = create_instrument("#steady\n1 Delta 1\n#envelope\n\
+            attack 0.1\ndecay 0.1\nsustain 0.5\nrelease 0.2\n", "test", 1.0,
+            &mut Diagnostics::new());
+        // The loudest sample, at the end of the attack, is brought to 1
+        let options = Options { normalization: Normalization::Peak(0.0),
+            ..Options::default() };
+        let samples = render(&inst, 0.0, &options);
+        // A beat of note and 0.2 seconds of release
+        assert_eq!(samples.len(), 22_050 + 8_820);
+        for t in (0..samples.len()).step_by(90) {
+            let time = t as f32 / 44_100.0;
+            let expected = if time < 0.1 {
+                time / 0.1
+            } else if time < 0.2 {
+                1.0 - 0.5 * (time - 0.1) / 0.1
+            } else if time < 0.5 {
+                0.5
+            } else {
+                0.5 * (1.0 - (time - 0.5) / 0.2)
             };
+            assert!((samples[t] - expected).abs() < 1e-4,
+                "sample {}: {} is not {}", t, samples[t], expected);
+        }
+    }
+    #[test]
+    fn pan_positions() {
+        let inst = Instrument::new("sine", 1.0, &mut Diagnostics::new())
+            .unwrap();
+        let stereo = Options { channels: 2, ..Options::default() };
+        let energy = |samples: &[f32]| samples.iter().map(|s| s * s)
+            .sum::<f32>();
+        let channel = |samples: &[f32], c: usize| samples.iter().skip(c)
+            .step_by(2).copied().collect::<Vec<_>>();
+
+        let left = render(&inst, -1.0, &stereo);
+        assert!(energy(&channel(&left, 0)) > 0.0);
+        assert!(channel(&left, 1).iter().all(|&s| s == 0.0));
+        let right = render(&inst, 1.0, &stereo);
+        assert!(channel(&right, 0).iter().all(|&s| s == 0.0));
+        assert!(energy(&channel(&right, 1)) > 0.0);
 
-            self.output.write(&mut cqt);
+        // Each side of a centered note carries half the power of the note
+        let mono = energy(&render(&inst, 0.0, &Options::default()));
+        let center = render(&inst, 0.0, &stereo);
+        for c in 0..2 {
+            let ratio = energy(&channel(&center, c)) / mono;
+            assert!((ratio - 0.5).abs() < 1e-4, "channel {}: {}", c, ratio);
         }
-        self.notes[self.active].clear();
-        self.active = (self.active + 1) % BD_SIZE;
-        self.low_time += 1;
     }
 }
-
-fn get_duration_from_length(note_length:u32) -> u32 {
-    BD_SIZE as u32 / note_length
-}
\ No newline at end of file
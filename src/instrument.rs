@@ -1,4 +1,5 @@
-use crate::errors::{Result, ParseError};
+use crate::errors::{Diagnostics, Line, ParseErrorKind, Result, Severity,
+    ThrorganError};
 use std::{fs, str};
 use std::ops::Mul;
 use std::collections::HashMap;
@@ -33,6 +34,16 @@ impl Sound {
         Sound {freq, vol}
     }
 
+    /// Get the frequency distribution of the sound.
+    pub fn smear(&self) -> &Smear {
+        &self.freq
+    }
+
+    /// Get the total volume of the sound.
+    pub fn vol(&self) -> f32 {
+        self.vol
+    }
+
     /// Get the power of the sound's frequency at frequency `freq`
     pub fn get_power(&self, freq: f32) -> f32 {
         self.vol * match self.freq {
@@ -45,42 +56,103 @@ impl Sound {
                 }
             },
             Smear::Gaussian(mean, sigma) => {
-                f32::exp(f32::powf(mean - freq, 2.0) / (2.0 * sigma * sigma)) / 
+                f32::exp(-f32::powf(mean - freq, 2.0) / (2.0 * sigma * sigma)) /
                 (sigma * 2.50662827463)
             },
         }
     }
 }
 
+/// Describes how the volume of a note rises and settles while it is held. The
+/// release is the instrument's ring-down time.
+#[derive(Clone, Copy)]
+pub struct Envelope {
+    attack: f32, // Time to reach full volume in seconds
+    decay: f32, // Time to fall from full volume to the sustain level in seconds
+    sustain: f32, // Fraction of full volume held until the note ends
+}
+
+impl Envelope {
+    /// Make an envelope that is at full volume from the start.
+    pub fn flat() -> Envelope {
+        Envelope { attack: 0.0, decay: 0.0, sustain: 1.0 }
+    }
+
+    /// Get the volume prefactor `t` seconds after the note starts.
+    pub fn level(&self, t: f32) -> f32 {
+        if t < self.attack {
+            t / self.attack
+        }
+        else if t < self.attack + self.decay {
+            1.0 - (1.0 - self.sustain) * (t - self.attack) / self.decay
+        }
+        else {
+            self.sustain
+        }
+    }
+}
+
 pub struct Instrument {
     steady_mult: Vec<Sound>, // Ring-down time in seconds
     reverb: f32, // Ring-down time in seconds
+    envelope: Envelope,
     vol: f32, // Volume of the instrument
+    pan: f32, // Position from -1 (left) to 1 (right)
 }
 
 impl Instrument {
     /// Make an instrument from a file `name` with assigned volume `vol`.
-    pub fn new(name: &str, vol: f32) -> Result<Instrument> {
+    /// Problems in the file are recorded in `diagnostics`, and only a file
+    /// that cannot be read is an error.
+    pub fn new(name: &str, vol: f32, diagnostics: &mut Diagnostics)
+    -> Result<Instrument> {
         let inst_contents : String;
-        let file_text = match get_prefab_text(name) {
-            Some(file_text) => file_text,
+        let path = format!("instruments/{}.inst", name);
+        let (file_text, file) = match get_prefab_text(name) {
+            Some(file_text) => (file_text, name),
             None => {
-                inst_contents = fs::read_to_string(
-                    format!("instruments/{}.inst", name))?;
-                &inst_contents[..]
+                inst_contents = fs::read_to_string(&path)
+                    .map_err(|e| ThrorganError::read(&path, e))?;
+                (&inst_contents[..], &path[..])
             }
         };
-        create_instrument(file_text, name, vol)
+        Ok(create_instrument(file_text, file, vol, diagnostics))
     }
 
-    /// Verify the instrument after its initialization. Conumes self and returns
-    /// it.
-    fn verify(self, name: &str) -> Result<Instrument> {
+    /// Make a silent instrument, which stands in for one that could not be
+    /// loaded.
+    pub fn empty() -> Instrument {
+        Instrument { steady_mult:Vec::new(), reverb: 0.0,
+            envelope: Envelope::flat(), vol: 1.0, pan: 0.0 }
+    }
+
+    /// Verify the instrument after its initialization.
+    fn verify(&self, name: &str) -> Result<()> {
         if self.steady_mult.len() == 0 || self.reverb == 0.0 || 
         self.vol < 0.0 || self.vol > 1.0 {
-            return Err(ParseError::ModeNotHit(name.to_string()).into());
+            return Err(ThrorganError::file(name, ParseErrorKind::ModeNotHit));
         }
-        Ok(self)
+        Ok(())
+    }
+
+    /// Get the ring-down time of the instrument in seconds.
+    pub fn reverb(&self) -> f32 {
+        self.reverb
+    }
+
+    /// Get the attack, decay and sustain of the instrument.
+    pub fn envelope(&self) -> Envelope {
+        self.envelope
+    }
+
+    /// Get the stereo position of the instrument, from -1 (left) to 1 (right).
+    pub fn pan(&self) -> f32 {
+        self.pan
+    }
+
+    /// Place the instrument at `pan`, from -1 (left) to 1 (right).
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan;
     }
 
     /// Generate the steady-state sounds of the instrument for a given frequency
@@ -106,98 +178,134 @@ fn get_prefab_text(name: &str) -> Option<&str> {
 /// Mode for parsing instrument files
 enum Mode {
     Steady,
-    End
+    Envelope,
+    End,
+    Invalid, // Lines are skipped, since the mode has been reported
 }
 
-/// Make an instrument from a file
-fn create_instrument(lines: &str, name: & str, vol: f32)
--> Result<Instrument> {
+/// Make an instrument from the text of the file `name`. Problems are recorded
+/// in `diagnostics` and bad lines are skipped.
+pub fn create_instrument(lines: &str, name: & str, vol: f32,
+    diagnostics: &mut Diagnostics) -> Instrument {
     let mut mode : Option<Mode> = None;
-    let mut ret = Instrument { steady_mult:Vec::new(), reverb: 0.0, vol };
+    let mut ret = Instrument { vol, ..Instrument::empty() };
+    let errors = diagnostics.count(Severity::Error);
 
-    for (num, line) in lines.lines().enumerate() {
-        if line.is_empty() {
+    for (num, text) in lines.lines().enumerate() {
+        if text.is_empty() {
             continue
         }
+        let line = Line::new(name, num, text);
 
-        if match line.chars().next() {
+        if match text.chars().next() {
             Some(c) => c,
             None => '?'
         } == '#' {
             // Change the mode
-            mode = match &line[1..] {
-                "steady" => Some(Mode::Steady),
-                "end" => Some(Mode::End),
-                _ => return Err(
-                    ParseError::InvalidMode(name.to_string(), num).into()),
-            };
+            mode = Some(match &text[1..] {
+                "steady" => Mode::Steady,
+                "envelope" => Mode::Envelope,
+                "end" => Mode::End,
+                _ => {
+                    diagnostics.error(line.error(ParseErrorKind::InvalidMode,
+                        text));
+                    Mode::Invalid
+                },
+            });
         }
 
         else {
-            match mode {
-                None => return Err(
-                    ParseError::NoModeDeclared(name.to_string(), num).into()),
-                Some(ref m) => match m {
-                    Mode::Steady => {
-                        let mut items = line.split_whitespace();
-                        let vol = match items.next() {
-                            Some(s) => s,
-                            None => return Err(ParseError::InvalidSound(
-                                name.to_string(), num).into()),
-                        }.parse::<f32>()?;
-                        let smear = match items.next() {
-                            Some(s) => s,
-                            None => return Err(ParseError::InvalidSound(
-                                name.to_string(), num).into()),
-                        };
-                        let freq = match items.next() {
-                            Some(s) => s,
-                            None => return Err(ParseError::InvalidSound(
-                                name.to_string(), num).into()),
-                        }.parse::<f32>()?;
-                        ret.steady_mult.push(Sound {
-                            freq: match smear{
-                                "Delta" => Smear::Delta(freq),
-                                "Gaussian" => {
-                                    let sigma = match items.next() {
-                                        Some(s) => s,
-                                        None => return Err(ParseError::
-                                            InvalidSound(name.to_string(), num)
-                                            .into()),
-                                    }.parse::<f32>()?;
-                                    Smear::Gaussian(freq, sigma)
-                                },
-                                _ => return Err(ParseError::InvalidSound(
-                                    name.to_string(), num).into()),
-                            },
-                            vol,
-                        });
-                    }
-                    Mode::End => {
-                        let mut items = line.split_whitespace();
-                        match match items.next() {
-                            Some(s) => s,
-                            None => continue
-                        } {
-                            "reverb-time" => { 
-                                ret.reverb = match items.next() {
-                                    Some(n) => n,
-                                    None => return Err(ParseError::InvalidValue(
-                                        name.to_string(), num).into())
-                                }.parse()?;
-                            },
-                            _ => return Err(ParseError::InvalidKey(
-                                name.to_string(), num).into())
-                        }
+            let result = match mode {
+                None => Err(line.error(ParseErrorKind::NoModeDeclared, text)),
+                Some(ref m) => read_line(&mut ret, m, &line, diagnostics),
+            };
+            diagnostics.check(result);
+        }
+    }
+    // An instrument with bad lines is likely to be missing what they set,
+    // which has already been reported
+    if diagnostics.count(Severity::Error) == errors {
+        diagnostics.check(ret.verify(name));
+    }
+    ret
+}
+
+/// Read a line of an instrument file in mode `mode` into `inst`.
+fn read_line(inst: &mut Instrument, mode: &Mode, line: &Line,
+    diagnostics: &mut Diagnostics) -> Result<()> {
+    let mut items = line.text().split_whitespace();
+    match mode {
+        Mode::Steady => {
+            let vol = line.parse::<f32>(line.expect(items.next(),
+                ParseErrorKind::InvalidSound)?, ParseErrorKind::InvalidSound)?;
+            let smear = line.expect(items.next(),
+                ParseErrorKind::InvalidSound)?;
+            let freq = line.parse::<f32>(line.expect(items.next(),
+                ParseErrorKind::InvalidSound)?, ParseErrorKind::InvalidSound)?;
+            inst.steady_mult.push(Sound {
+                freq: match smear{
+                    "Delta" => Smear::Delta(freq),
+                    "Gaussian" => {
+                        let sigma = line.parse::<f32>(line.expect(items.next(),
+                            ParseErrorKind::InvalidSound)?,
+                            ParseErrorKind::InvalidSound)?;
+                        Smear::Gaussian(freq, sigma)
+                    },
+                    _ => return Err(line.error(ParseErrorKind::InvalidSound,
+                        smear)),
+                },
+                vol,
+            });
+        }
+        Mode::Envelope => {
+            let key = match items.next() {
+                Some(s) => s,
+                None => return Ok(())
+            };
+            let value_text = line.expect(items.next(),
+                ParseErrorKind::KeyWithoutValue)?;
+            let value = line.parse::<f32>(value_text,
+                ParseErrorKind::InvalidValue)?;
+            // Every instrument must ring down, so a release has to be longer
+            // than zero
+            if value < 0.0 || (key == "sustain" && value > 1.0) ||
+                (key == "release" && value == 0.0) {
+                return Err(line.error(ParseErrorKind::InvalidValue,
+                    value_text));
+            }
+            match key {
+                "attack" => inst.envelope.attack = value,
+                "decay" => inst.envelope.decay = value,
+                "sustain" => inst.envelope.sustain = value,
+                "release" => inst.reverb = value,
+                _ => return Err(line.error(ParseErrorKind::InvalidKey, key))
+            }
+        }
+        Mode::End => {
+            let key = match items.next() {
+                Some(s) => s,
+                None => return Ok(())
+            };
+            match key {
+                "reverb-time" => {
+                    let value_text = line.expect(items.next(),
+                        ParseErrorKind::InvalidValue)?;
+                    inst.reverb = line.parse(value_text,
+                        ParseErrorKind::InvalidValue)?;
+                    if inst.reverb <= 0.0 {
+                        return Err(line.error(ParseErrorKind::InvalidValue,
+                            value_text));
                     }
-                }
+                },
+                _ => return Err(line.error(ParseErrorKind::InvalidKey, key))
             }
         }
+        Mode::Invalid => return Ok(()),
     }
-    ret.verify(name)
+    diagnostics.unused(line, items.next());
+    Ok(())
 }
 
-
 /// Struct for memoizing all damp values
 pub struct Damp {
     memo: HashMap<(u32, u32), f32>,
@@ -214,10 +322,33 @@ impl Damp {
         match self.memo.get(&(t, dur)) {
             Some(d) => d.clone(),
             None => {
-                let d = f32::max((dur - t) as f32, 0.0) / (dur as f32);
+                let d = dur.saturating_sub(t) as f32 / (dur as f32);
                 self.memo.insert((t, dur), d);
                 d.clone()
             }
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_release_rejected() {
+        for text in &["#envelope\nrelease 0", "#end\nreverb-time 0"] {
+            let mut diagnostics = Diagnostics::new();
+            create_instrument(&format!("#steady\n1 Delta 1\n{}\n", text),
+                "test", 1.0, &mut diagnostics);
+            match diagnostics.problems() {
+                [(Severity::Error, ThrorganError::Parse { kind, span, .. })]
+                => {
+                    assert_eq!(*kind, ParseErrorKind::InvalidValue);
+                    let span = span.as_ref().unwrap();
+                    assert_eq!((span.line, span.token.as_str()), (4, "0"));
+                },
+                problems => panic!("Expected one error, got {:?}", problems),
+            }
+        }
+    }
+}
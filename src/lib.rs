@@ -5,37 +5,226 @@
 //! `throrgan` instruments, and compiles them into .wav files that can be used, 
 //! for example, as a royalty-free soundtrack for video games.
 
+use std::fs;
 use std::path::Path;
-use std::{fs, io};
+use std::io::{Seek, Write};
 
 mod errors;
 mod parse;
 mod instrument;
 mod generator;
 mod output;
+mod options;
+mod pitch;
+mod render;
+mod timeline;
+mod tuning;
+mod mastering;
+mod encode;
+mod flac;
+mod vorbis;
 
-/// Compiles a file and generates a wave file
+pub use errors::{Diagnostics, ParseErrorKind, Result, Severity, Span,
+    ThrorganError};
+pub use options::{FileFormat, Normalization, Options, Overwrite, Renderer,
+    SampleFormat};
+pub use output::AudioBuffer;
+pub use parse::SongInfo;
+
+use encode::{Collect, Encoder};
+use output::Output;
+
+/// The name given to songs compiled from a string in error messages
+const SOURCE_NAME: &str = "<source>";
+
+/// Compiles a file and generates an audio file, whose type is chosen by the
+/// extension of `output_file`
 /// # Errors
-/// - Returns a `FileAlreadyExists` error if `output_file` already exists
-/// - Returns a `FileDoesNotExist` error if `input_file` does not exist
+/// - Returns a `ThrorganError::FileAlreadyExists` error if `output_file`
+///   already exists, unless `Options::overwrite` allows replacing it
+/// - Returns a `ThrorganError::CannotWrite` error if `output_file` cannot be
+///   written. The song is written to a temporary file first, so an existing
+///   file is only replaced once the new one is complete.
+/// - Returns a `ThrorganError::Io` error if `input_file` cannot be read
+/// - Returns a `ThrorganError::InvalidOption` error if the type of
+///   `output_file` or the settings in `options` are not supported
+/// - Returns a `ThrorganError::Diagnostics` error listing every problem found
+///   in the song and its instruments if any of them is an error. Warnings are
+///   not reported when the song compiles; use `compile_with` to get them.
 pub fn compile(input_file: &str, output_file: &str) 
--> errors::Result<()> {
+-> Result<()> {
+    compile_with(input_file, output_file, &Options::default()).map(|_| ())
+}
+
+/// Compiles a file and generates an audio file using the settings in
+/// `options`, returning the warnings found in the song
+/// # Errors
+/// See `compile`.
+pub fn compile_with(input_file: &str, output_file: &str, options: &Options)
+-> Result<Diagnostics> {
     // Check if the output file exists
-    /*if Path::new(output_file).exists() {
-        return Err(io::Error::new(io::ErrorKind::AlreadyExists, 
-            format!("The file {} already exists", input_file)).into());
-    }*/
-    if output_file.len() < 4 || !output_file[output_file.len()-4..].eq(".wav") {
-        return Err(io::Error::new(io::ErrorKind::InvalidInput, 
-            "You must specify a valid .wav file as output").into());
+    if options.overwrite == Overwrite::Never && Path::new(output_file).exists() {
+        return Err(ThrorganError::FileAlreadyExists {
+            path: output_file.to_string() });
     }
+    output::file_format(Some(output_file), options)?;
+    check_options(options)?;
 
     // Open and read the input file
-    let contents: String = fs::read_to_string(input_file)?.parse()?;
+    let contents = read_input(input_file)?;
+
+    let (song, warnings) = parse::read_song(&contents, input_file, options)?;
+
+    let output = Output::new(output_file, options)?;
+    parse::generate(&song, output, options)?;
+
+    Ok(warnings)
+}
 
-    let header = parse::get_header(&contents, input_file)?;
+/// Compiles the text of a song into samples in memory, and gets them with the
+/// warnings found in the song. The samples are floats, so
+/// `options.sample_format`, `options.file_format` and `options.dither` are
+/// ignored.
+/// # Errors
+/// Returns the same errors as `compile` for problems in the song itself.
+pub fn compile_str(source: &str, options: &Options)
+-> Result<(AudioBuffer, Diagnostics)> {
+    check_options(options)?;
+    let options = Options { sample_format: SampleFormat::Float32,
+        ..options.clone() };
+    let (song, warnings) = parse::read_song(source, SOURCE_NAME, &options)?;
+
+    let mut samples = Vec::new();
+    let output = Output::with_encoder(|_| Ok(Box::new(Collect {
+        samples: &mut samples }) as Box<dyn Encoder>), &options)?;
+    parse::generate(&song, output, &options)?;
+
+    Ok((AudioBuffer { sample_rate: options.sample_rate,
+        channels: options.channels, samples }, warnings))
+}
+
+/// Compiles the text of a song and writes it to `sink` in the format set by
+/// `options.file_format`, or as a .wav file if it is not set, returning the
+/// warnings found in the song.
+/// # Errors
+/// Returns the same errors as `compile` for problems in the song itself, and
+/// any error from writing to `sink`.
+pub fn compile_to_writer<W: Write + Seek>(source: &str, sink: W,
+    options: &Options) -> Result<Diagnostics> {
+    let format = output::file_format(None, options)?;
+    check_options(options)?;
+    let (song, warnings) = parse::read_song(source, SOURCE_NAME, options)?;
 
-    parse::generate(&header, &contents, input_file, output_file)?;
+    let output = Output::with_encoder(|info| encode::new_encoder(format, sink,
+        info), options)?;
+    parse::generate(&song, output, options)?;
+    Ok(warnings)
+}
 
+/// Check that the output described by `options` can be written.
+fn check_options(options: &Options) -> Result<()> {
+    if !(8_000..=192_000).contains(&options.sample_rate) {
+        return Err(ThrorganError::InvalidOption(
+            format!("Unsupported sample rate {}", options.sample_rate)));
+    }
+    if !(1..=2).contains(&options.channels) {
+        return Err(ThrorganError::InvalidOption(
+            format!("Unsupported channel count {}", options.channels)));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Read the song in `input_file`.
+fn read_input(input_file: &str) -> Result<String> {
+    fs::read_to_string(input_file).map_err(
+        |e| ThrorganError::read(input_file, e))
+}
+
+/// Reads a file and checks every note without generating any audio, returning
+/// the warnings found
+/// # Errors
+/// Returns the same errors as `compile` for problems in the song itself.
+pub fn check(input_file: &str, options: &Options) -> Result<Diagnostics> {
+    let contents = read_input(input_file)?;
+    let (_, warnings) = parse::read_song(&contents, input_file, options)?;
+    Ok(warnings)
+}
+
+/// Reads a file and summarizes the song it describes
+/// # Errors
+/// See `check`.
+pub fn info(input_file: &str, options: &Options) -> Result<SongInfo> {
+    let contents = read_input(input_file)?;
+    let (song, _) = parse::read_song(&contents, input_file, options)?;
+    Ok(parse::info(&song, options))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SONG: &str = "#instruments
+sine 1
+
+#signature
+tempo 120
+
+#music
+0 A4 0 1 1
+";
+
+    #[test]
+    fn compile_in_memory() {
+        let options = Options { renderer: Renderer::Oscillator, channels: 2,
+            ..Options::default() };
+        let (buffer, warnings) = compile_str(SONG, &options).unwrap();
+        // Half a second of note and half a second of release
+        assert_eq!(buffer.frames(), 44_100);
+        assert!(buffer.samples.iter().all(|s| s.abs() <= 1.0));
+        assert!(warnings.problems().is_empty());
+
+        let mut sink = Cursor::new(Vec::new());
+        compile_to_writer(SONG, &mut sink, &options).unwrap();
+        let wav = sink.into_inner();
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav.len(), 44 + 44_100 * 2 * 2);
+    }
+
+    #[test]
+    fn every_problem_reported() {
+        let song = SONG.replace("tempo 120", "tempo 120 x") +
+            "0 Q9 1 1 1\n0 A4 2 1 2\n";
+        let options = Options { renderer: Renderer::Oscillator,
+            ..Options::default() };
+        match compile_str(&song, &options) {
+            Err(ThrorganError::Diagnostics(diagnostics)) => {
+                assert_eq!(diagnostics.count(Severity::Error), 2);
+                assert_eq!(diagnostics.count(Severity::Warning), 1);
+            },
+            _ => panic!("Expected every problem in the song"),
+        }
+    }
+
+    #[test]
+    fn warnings_returned() {
+        let song = SONG.replace("tempo 120", "tempo 120 x");
+        let options = Options { renderer: Renderer::Oscillator,
+            ..Options::default() };
+        let (_, warnings) = compile_str(&song, &options).unwrap();
+        assert_eq!(warnings.count(Severity::Warning), 1);
+        let warnings = compile_to_writer(&song, Cursor::new(Vec::new()),
+            &options).unwrap();
+        assert_eq!(warnings.count(Severity::Warning), 1);
+    }
+    #[test]
+    fn out_of_order_notes() {
+        let options = Options { renderer: Renderer::Oscillator,
+            ..Options::default() };
+        let (in_order, _) = compile_str(&(SONG.to_string() +
+            "0 C5 1 1 1\n0 E5 2 1 1\n"), &options).unwrap();
+        let (out_of_order, _) = compile_str(&SONG.replace("0 A4 0 1 1\n",
+            "0 E5 2 1 1\n0 C5 1 1 1\n0 A4 0 1 1\n"), &options).unwrap();
+        assert_eq!(in_order.samples, out_of_order.samples);
+    }
+}
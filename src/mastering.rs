@@ -0,0 +1,179 @@
+//! # Mastering
+//!
+//! This file holds the processing applied to the rendered song on its way to
+//! the output: loudness measurement for normalization, a soft limiter that
+//! keeps loud passages from clipping, and dither for integer sample formats.
+
+use std::f64::consts::PI;
+
+const LIMIT_THRESHOLD: f32 = 0.9; // Level above which the limiter bends
+const BLOCK_SECONDS: f64 = 0.4; // Length of a loudness gating block
+const ABSOLUTE_GATE: f64 = -70.0; // Quietest block counted, in LUFS
+const RELATIVE_GATE: f64 = -10.0; // Quietest block counted, in LU below the
+                                  // ungated loudness
+
+/// Squash `x` smoothly so that it never exceeds full scale. Samples below
+/// `LIMIT_THRESHOLD` pass unchanged, and above it the level bends towards 1
+/// along a tanh curve that meets the straight line with the same slope.
+pub fn soft_limit(x: f32) -> f32 {
+    let level = x.abs();
+    if level <= LIMIT_THRESHOLD {
+        return x;
+    }
+    let room = 1.0 - LIMIT_THRESHOLD;
+    x.signum() * (LIMIT_THRESHOLD + room * ((level - LIMIT_THRESHOLD) / room)
+        .tanh())
+}
+
+/// Get the largest absolute sample of `samples`.
+pub fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0, |max, x| f32::max(max, x.abs()))
+}
+
+/// A second-order IIR filter in direct form I.
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3], // a[0] is normalized to 1
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Biquad {
+        Biquad { b, a, x: [0.0; 2], y: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[1] * self.y[0] - self.a[2] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Make the two stages of the K-weighting filter of ITU-R BS.1770 at
+/// `sample_rate`: a high shelf modelling the head, then a high pass.
+fn k_weighting(sample_rate: u32) -> (Biquad, Biquad) {
+    let rate = sample_rate as f64;
+
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347,
+        0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0],
+        [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+
+    (shelf, high_pass)
+}
+
+/// Measure the integrated loudness of `samples` in LUFS following ITU-R
+/// BS.1770, where `samples` holds frames of `channels` interleaved samples.
+/// Returns `None` if the song is too short or too quiet to be measured.
+pub fn integrated_loudness(samples: &[f32], channels: usize, sample_rate: u32)
+-> Option<f32> {
+    let frames = samples.len() / channels;
+
+    // Mean square of the K-weighted signal, summed over channels, in steps of
+    // a quarter block
+    let step = (BLOCK_SECONDS * sample_rate as f64 / 4.0) as usize;
+    if step == 0 || frames < 4 * step {
+        return None;
+    }
+    let mut steps = vec![0.0; frames / step];
+    for c in 0..channels {
+        let (mut shelf, mut high_pass) = k_weighting(sample_rate);
+        for (t, frame) in samples.chunks_exact(channels).enumerate() {
+            let y = high_pass.process(shelf.process(frame[c] as f64));
+            if let Some(power) = steps.get_mut(t / step) {
+                *power += y * y;
+            }
+        }
+    }
+
+    // Blocks overlap by three quarters
+    let blocks: Vec<f64> = steps.windows(4)
+        .map(|w| w.iter().sum::<f64>() / (4 * step) as f64).collect();
+    let loudness = |power: f64| -0.691 + 10.0 * power.log10();
+    let gated_mean = |gate: f64| {
+        let kept: Vec<f64> = blocks.iter().copied()
+            .filter(|&p| loudness(p) > gate).collect();
+        if kept.is_empty() {
+            None
+        } else {
+            Some(kept.iter().sum::<f64>() / kept.len() as f64)
+        }
+    };
+
+    let ungated = gated_mean(ABSOLUTE_GATE)?;
+    let gated = gated_mean(loudness(ungated) + RELATIVE_GATE)?;
+    Some(loudness(gated) as f32)
+}
+
+/// Makes triangular (TPDF) dither noise to add before rounding to integer
+/// samples, which turns the rounding error into a steady hiss instead of
+/// distortion that follows the music.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new() -> Dither {
+        Dither { state: 0x9E37_79B9 }
+    }
+
+    /// Get a uniform value from 0 to 1 with a xorshift generator. The noise
+    /// only has to be uncorrelated with the music, so a fixed seed is fine.
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32
+    }
+
+    /// Get the next noise value, from -1 to 1 least significant bits.
+    pub fn next(&mut self) -> f32 {
+        self.uniform() - self.uniform()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_loudness() {
+        // A full-scale 997 Hz sine in one channel reads -3.01 LUFS.
+        let rate = 48_000;
+        let samples: Vec<f32> = (0..5 * rate)
+            .map(|t| (2.0 * PI * 997.0 * t as f64 / rate as f64).sin() as f32)
+            .collect();
+        let loudness = integrated_loudness(&samples, 1, rate as u32).unwrap();
+        assert!((loudness + 3.01).abs() < 0.05, "{}", loudness);
+
+        // Silence cannot be measured.
+        assert_eq!(integrated_loudness(&[0.0; 96_000], 2, 48_000), None);
+    }
+
+    #[test]
+    fn limiter_bounds() {
+        assert_eq!(soft_limit(0.5), 0.5);
+        assert_eq!(soft_limit(-0.9), -0.9);
+        for &x in [1.0f32, 2.0, 10.0, 1000.0].iter() {
+            assert!(soft_limit(x) <= 1.0);
+            assert!(soft_limit(x) > LIMIT_THRESHOLD);
+            assert_eq!(soft_limit(-x), -soft_limit(x));
+        }
+        assert!(soft_limit(2.0) > soft_limit(1.0));
+    }
+}
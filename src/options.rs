@@ -0,0 +1,113 @@
+//! # Options
+//! 
+//! This file holds the settings that control how a song is compiled.
+
+/// Selects the backend used to turn notes into samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    /// Build a log-frequency spectrum of the song and resynthesize it.
+    Spectral,
+    /// Sum one sinusoid per partial directly. Faster, and useful as a
+    /// reference to compare the spectral renderer against.
+    Oscillator,
+}
+
+/// Selects how each sample is stored in the output file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+    /// 16-bit signed integers
+    Int16,
+    /// 24-bit signed integers
+    Int24,
+    /// 32-bit floats
+    Float32,
+}
+
+/// Selects the type of file written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    /// A RIFF .wav file
+    Wav,
+    /// A lossless compressed .flac file. Float samples cannot be stored.
+    Flac,
+    /// A lossy compressed Ogg Vorbis .ogg file. The sample format only sets
+    /// the precision of the samples before they are coded.
+    Ogg,
+    /// Little-endian samples with no header, interleaved by channel
+    Raw,
+}
+
+impl FileFormat {
+    /// Get the format usually given by the extension of `path`: `.wav`,
+    /// `.flac`, `.ogg`, or `.raw` or `.pcm` for raw samples.
+    pub fn from_path(path: &str) -> Option<FileFormat> {
+        let extension = path.rsplit_once('.')?.1.to_lowercase();
+        match extension.as_str() {
+            "wav" => Some(FileFormat::Wav),
+            "flac" => Some(FileFormat::Flac),
+            "ogg" => Some(FileFormat::Ogg),
+            "raw" | "pcm" => Some(FileFormat::Raw),
+            _ => None,
+        }
+    }
+}
+
+/// Selects what happens when the output file already exists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overwrite {
+    /// Fail with `ThrorganError::FileAlreadyExists`.
+    Never,
+    /// Replace the file once the new song has been fully written.
+    Always,
+}
+
+/// Selects how the level of the song is set before it is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Normalization {
+    /// Write samples at a fixed gain, leaving about 2.3 dB of headroom for a
+    /// note at full volume.
+    Off,
+    /// Scale the song so that its loudest sample sits at the given level in
+    /// dBFS. The limiter is not applied, so levels above 0 dBFS clip.
+    Peak(f32),
+    /// Scale the song so that its integrated loudness (ITU-R BS.1770) is the
+    /// given level in LUFS.
+    Loudness(f32),
+}
+
+/// Settings for `compile_with`. Use `Options::default()` and change the
+/// fields of interest.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub renderer: Renderer,
+    /// Reject music sections whose notes are not written in order of their
+    /// onsets, instead of sorting them.
+    pub strict: bool,
+    /// Samples per second of the output, which the song is rendered at.
+    pub sample_rate: u32,
+    pub sample_format: SampleFormat,
+    /// Channels of the output: 1 for mono, or 2 for stereo, where notes are
+    /// panned between the left and right channels.
+    pub channels: u16,
+    /// Normalizing needs the whole song before anything is written, so it is
+    /// held in memory until rendering finishes.
+    pub normalization: Normalization,
+    /// Bend samples near full scale smoothly instead of clipping them. Not
+    /// used with `Normalization::Peak`.
+    pub limiter: bool,
+    /// Add triangular dither noise when rounding to integer samples.
+    pub dither: bool,
+    /// Type of file to write, or `None` to choose by the extension of the
+    /// output file.
+    pub file_format: Option<FileFormat>,
+    pub overwrite: Overwrite,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options { renderer: Renderer::Spectral, strict: false,
+            sample_rate: 44_100, sample_format: SampleFormat::Int16,
+            channels: 1, normalization: Normalization::Off, limiter: true,
+            dither: true, file_format: None, overwrite: Overwrite::Never }
+    }
+}
@@ -1,46 +1,360 @@
-use std::fs::File;
-use std::path::Path;
-use rustfft::{FftPlanner, num_complex::Complex};
-
-pub const FREQ_SIZE: usize = 1024;
-pub const SAMPLE_RATE: u32 = 44_100;
-
-pub struct Output {
-    out_file: File,
-    header: wav::Header
-}
-
-impl Output {
-    pub fn new(output_dir: &str) -> Output {
-        let header = wav::Header {
-            audio_format: 1,
-            channel_count: 1, // If you do 2 channels, repeat right left right
-                                // left in the data.
-            sampling_rate: SAMPLE_RATE,
-            bytes_per_second: 88200,
-            bytes_per_sample: 2,
-            bits_per_sample: 16,
+use crate::encode::{Encoder, Samples, StreamInfo, new_encoder};
+use crate::mastering::{Dither, integrated_loudness, peak, soft_limit};
+use crate::errors::{Result, ThrorganError};
+use crate::options::{FileFormat, Normalization, Options, Overwrite,
+    SampleFormat};
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const HEADROOM_GAIN: f32 = 0.763; // Gain when not normalizing, about -2.3 dB
+
+/// Get the type of file to write to `path`, either as set in `options` or from
+/// the extension of `path`. Songs written to something other than a file are
+/// .wav files unless `options` says otherwise.
+pub fn file_format(path: Option<&str>, options: &Options)
+-> Result<FileFormat> {
+    let format = match (options.file_format, path) {
+        (Some(format), _) => format,
+        (None, None) => FileFormat::Wav,
+        (None, Some(path)) => match FileFormat::from_path(path) {
+            Some(format) => format,
+            None => return Err(ThrorganError::InvalidOption(
+                "You must specify a .wav, .flac, .ogg, .raw or .pcm file as \
+                output".to_string())),
+        },
+    };
+    if format == FileFormat::Flac &&
+        options.sample_format == SampleFormat::Float32 {
+        return Err(ThrorganError::InvalidOption(
+            "FLAC cannot store float samples".to_string()));
+    }
+    Ok(format)
+}
+
+/// A song compiled in memory
+#[derive(Clone, Debug)]
+pub struct AudioBuffer {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Samples from -1 to 1, interleaved by channel
+    pub samples: Vec<f32>,
+}
+
+impl AudioBuffer {
+    /// Get the number of samples in each channel.
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+}
+
+/// Number of temporary files named so far by this process
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Get a new temporary file for a song bound for `path` to be written to. It
+/// sits in the same directory so that it can be renamed over `path` in one
+/// step, and is named after the process and a counter so that songs written
+/// to the same path at once each have their own.
+fn temporary_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or("output".into(),
+        |n| n.to_string_lossy());
+    let count = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+    path.with_file_name(format!(".{}.{}.{}.tmp", name, process::id(), count))
+}
+
+/// Sets the level of the song and streams it into an encoder. When writing a
+/// file, the encoder writes a temporary file and `finalize` completes it and
+/// moves it to the output path, so a song that fails to render never replaces
+/// an existing file. If the output is dropped without being finalized, the
+/// temporary file is deleted. When normalizing, samples are held back until
+/// `finalize`, once the level of the whole song is known.
+pub struct Output<'a> {
+    encoder: Option<Box<dyn Encoder + 'a>>, // `None` once finalized
+    info: StreamInfo,
+    normalization: Normalization,
+    limiter: bool,
+    dither: Option<Dither>,
+    pending: Vec<f32>, // Interleaved samples waiting for normalization
+    file: Option<(PathBuf, PathBuf)>, // Output path and temporary file
+    overwrite: Overwrite,
+}
+
+impl Output<'static> {
+    /// Make an output writing the file `output_dir`.
+    pub fn new(output_dir: &str, options: &Options) -> Result<Output<'static>> {
+        let format = file_format(Some(output_dir), options)?;
+        let path = PathBuf::from(output_dir);
+        let temporary = temporary_path(&path);
+        let out_file = BufWriter::new(File::create(&temporary).map_err(
+            |cause| ThrorganError::CannotWrite {
+                path: output_dir.to_string(), cause })?);
+        let mut output = Output::with_encoder(|info| new_encoder(format,
+            out_file, info), options)?;
+        output.file = Some((path, temporary));
+        Ok(output)
+    }
+}
+
+impl<'a> Output<'a> {
+    /// Make an output writing to the encoder made by `make_encoder` from the
+    /// description of the stream.
+    pub fn with_encoder<F>(make_encoder: F, options: &Options)
+    -> io::Result<Output<'a>>
+    where F: FnOnce(StreamInfo) -> io::Result<Box<dyn Encoder + 'a>> {
+        let info = StreamInfo { sample_rate: options.sample_rate,
+            channels: options.channels, format: options.sample_format };
+        Ok(Output {
+            encoder: Some(make_encoder(info)?), info,
+            normalization: options.normalization, limiter: options.limiter,
+            dither: if options.dither { Some(Dither::new()) } else { None },
+            pending: Vec::new(), file: None, overwrite: options.overwrite })
+    }
+
+    /// Append one chunk of samples to the data chunk. `channels` holds the
+    /// samples of each channel, all of the same length, which are interleaved
+    /// into frames. Samples run from -1 to 1 at full volume.
+    pub fn push(&mut self, channels: &[Vec<f32>]) -> io::Result<()> {
+        if channels.len() != self.info.channels as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "Wrong number of channels pushed to the output"));
+        }
+        let frames = channels.first().map_or(0, |c| c.len());
+        let interleaved: Vec<f32> = (0..frames)
+            .flat_map(|t| channels.iter().map(move |channel| channel[t]))
+            .collect();
+        if self.normalization == Normalization::Off {
+            self.write_samples(&interleaved, HEADROOM_GAIN)
+        } else {
+            self.pending.extend(interleaved);
+            Ok(())
+        }
+    }
+
+    /// Get the gain that brings the held-back samples to the level asked for.
+    /// Silence is left as it is.
+    fn normalization_gain(&self) -> f32 {
+        match self.normalization {
+            Normalization::Off => HEADROOM_GAIN,
+            Normalization::Peak(target) => match peak(&self.pending) {
+                p if p > 0.0 => 10f32.powf(target / 20.0) / p,
+                _ => 1.0,
+            },
+            Normalization::Loudness(target) => match integrated_loudness(
+                &self.pending, self.info.channels as usize,
+                self.info.sample_rate) {
+                Some(loudness) => 10f32.powf((target - loudness) / 20.0),
+                None => 1.0,
+            },
+        }
+    }
+
+    /// Scale interleaved samples by `gain`, limit, dither and write them.
+    fn write_samples(&mut self, samples: &[f32], gain: f32) -> io::Result<()> {
+        let encoder = match self.encoder {
+            Some(ref mut encoder) => encoder,
+            None => return Err(io::Error::other(
+                "Cannot write to an output that has been finalized")),
+        };
+        // Peak normalization already sets the loudest sample, which the
+        // limiter would only pull down again
+        let limiter = self.limiter &&
+            !matches!(self.normalization, Normalization::Peak(_));
+        let levels = samples.iter().map(|sample| {
+            let value = gain * sample;
+            if limiter { soft_limit(value) } else { value }
+        });
+        let scale = match self.info.format {
+            SampleFormat::Int16 => 32_767.0,
+            SampleFormat::Int24 => 8_388_607.0,
+            SampleFormat::Float32 => {
+                let samples = Samples::Float(levels.collect());
+                return encoder.write(&samples);
+            },
         };
-        Output {out_file: File::create(Path::new(output_dir)).unwrap(), header }
+        let dither = &mut self.dither;
+        let samples = Samples::Int(levels.map(|value| {
+            // Noise of up to one least significant bit either way
+            let noise = match dither {
+                Some(dither) => dither.next(),
+                None => 0.0,
+            };
+            (value * scale + noise).round().clamp(-scale - 1.0, scale) as i32
+        }).collect());
+        encoder.write(&samples)
+    }
+
+    /// Write any held-back samples, complete the file and move it to the
+    /// output path if there is one. Further writes after this are an error.
+    pub fn finalize(&mut self) -> Result<()> {
+        if self.encoder.is_none() {
+            return Ok(());
+        }
+        if !self.pending.is_empty() {
+            let gain = self.normalization_gain();
+            let samples = std::mem::take(&mut self.pending);
+            self.write_samples(&samples, gain)?;
+        }
+        if let Some(mut encoder) = self.encoder.take() {
+            if let Err(e) = encoder.finish() {
+                if let Some((_, ref temporary)) = self.file {
+                    let _ = fs::remove_file(temporary);
+                }
+                return Err(e.into());
+            }
+        }
+        if let Some((ref path, ref temporary)) = self.file {
+            let result = match self.overwrite {
+                Overwrite::Always => fs::rename(temporary, path),
+                Overwrite::Never => move_new(temporary, path),
+            };
+            let _ = fs::remove_file(temporary);
+            result.map_err(|cause| {
+                let path = path.to_string_lossy().into_owned();
+                match cause.kind() {
+                    io::ErrorKind::AlreadyExists =>
+                        ThrorganError::FileAlreadyExists { path },
+                    _ => ThrorganError::CannotWrite { path, cause },
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Move `temporary` to `path` unless `path` exists. The file is linked to
+/// `path`, which fails if a file was created there while the song was being
+/// compiled where renaming would replace it. File systems without links fall
+/// back to checking for `path` first.
+fn move_new(temporary: &Path, path: &Path) -> io::Result<()> {
+    match fs::hard_link(temporary, path) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            if path.exists() {
+                return Err(io::ErrorKind::AlreadyExists.into());
+            }
+            fs::rename(temporary, path)
+        },
+        result => result,
+    }
+}
+
+impl Drop for Output<'_> {
+    fn drop(&mut self) {
+        if let (Some(_), Some((_, temporary))) = (self.encoder.take(),
+            &self.file) {
+            let _ = fs::remove_file(temporary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::Collect;
+
+    /// Get a path in the temporary directory that no other test uses.
+    fn scratch(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("throrgan-{}-{}",
+            process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
     }
 
-    pub fn write(&mut self, cqt: &mut Vec<[Complex<f32>; FREQ_SIZE]>) {
+    #[test]
+    fn never_replaces_a_new_file() {
+        let path = scratch("race.wav");
+        let mut output = Output::new(path.to_str().unwrap(),
+            &Options::default()).unwrap();
+        let temporary = output.file.clone().unwrap().1;
+        output.push(&[vec![0.5; 100]]).unwrap();
+        // Another program writes the file while the song is compiled
+        fs::write(&path, b"theirs").unwrap();
+        match output.finalize() {
+            Err(ThrorganError::FileAlreadyExists { .. }) => (),
+            _ => panic!("Expected the file to be left alone"),
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"theirs");
+        assert!(!temporary.exists());
 
-        let mut planner = FftPlanner::<f32>::new();
-        let fft = planner.plan_fft_inverse(FREQ_SIZE);
+        let options = Options { overwrite: Overwrite::Always,
+            ..Options::default() };
+        let mut output = Output::new(path.to_str().unwrap(), &options)
+            .unwrap();
+        let temporary = output.file.clone().unwrap().1;
+        output.push(&[vec![0.5; 100]]).unwrap();
+        output.finalize().unwrap();
+        assert_eq!(&fs::read(&path).unwrap()[..4], b"RIFF");
+        assert!(!temporary.exists());
+        fs::remove_file(&path).unwrap();
+    }
 
-        println!("{:?}", cqt[0]);
-        fft.process(&mut cqt[0]);
-        println!("{:?}", cqt[0]);
-        println!("");
+    #[test]
+    fn temporary_files_differ() {
+        // Two songs written to one path at once must not share a file
+        let path = scratch("twice.wav");
+        let outputs: Vec<Output> = (0..2).map(|_| Output::new(
+            path.to_str().unwrap(), &Options::default()).unwrap()).collect();
+        let first = &outputs[0].file.as_ref().unwrap().1;
+        let second = &outputs[1].file.as_ref().unwrap().1;
+        assert_ne!(first, second);
+        assert!(first.exists() && second.exists());
+    }
 
-        // TO DO: Make sure resultant fft is real, then write into data.
-        // TO DO: Update docs.
+    /// Fails to complete the file.
+    struct Unfinished;
 
-        let data = wav::BitDepth::Sixteen((0..SAMPLE_RATE).map(
-            |x| (25000.0 * f32::sin(x as f32 / (SAMPLE_RATE as f32) * 
-                440.0 * 6.28)) as i16).collect());
+    impl Encoder for Unfinished {
+        fn write(&mut self, _: &Samples) -> io::Result<()> {
+            Ok(())
+        }
 
-        wav::write(self.header, &data, &mut self.out_file).unwrap();
+        fn finish(&mut self) -> io::Result<()> {
+            Err(io::Error::other("Disk full"))
+        }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn failed_files_removed() {
+        let path = scratch("failed.wav");
+        let temporary = temporary_path(&path);
+        fs::write(&temporary, b"partial").unwrap();
+        let mut output = Output::with_encoder(|_| Ok(Box::new(Unfinished)
+            as Box<dyn Encoder>), &Options::default()).unwrap();
+        output.file = Some((path.clone(), temporary.clone()));
+        assert!(output.finalize().is_err());
+        assert!(!temporary.exists());
+        assert!(!path.exists());
+
+        // Dropped before being finalized
+        let mut samples = Vec::new();
+        fs::write(&temporary, b"partial").unwrap();
+        let mut output = Output::with_encoder(|_| Ok(Box::new(Collect {
+            samples: &mut samples }) as Box<dyn Encoder>),
+            &Options::default()).unwrap();
+        output.file = Some((path.clone(), temporary.clone()));
+        drop(output);
+        assert!(!temporary.exists());
+    }
+
+    #[test]
+    fn peak_reaches_target() {
+        // Past the threshold of the limiter, which must leave it alone
+        for &target in &[-6.0, -0.5] {
+            let options = Options { normalization: Normalization::Peak(target),
+                sample_format: SampleFormat::Float32, ..Options::default() };
+            let mut samples = Vec::new();
+            let mut output = Output::with_encoder(|_| Ok(Box::new(Collect {
+                samples: &mut samples }) as Box<dyn Encoder>), &options)
+                .unwrap();
+            let song: Vec<f32> = (0..1000).map(|t| 0.3 * (t as f32 * 0.1)
+                .sin()).collect();
+            output.push(&[song]).unwrap();
+            output.finalize().unwrap();
+            drop(output);
+            let expected = 10f32.powf(target / 20.0);
+            assert!((peak(&samples) - expected).abs() < 1e-6,
+                "{} is not {}", peak(&samples), expected);
+        }
+    }
+}
@@ -5,15 +5,24 @@
 //! other tasks.
 
 use crate::generator::Breakdown;
+use crate::timeline::{MeterMap, TempoMap, MAX_TICK, TICKS_PER_BEAT};
 use crate::instrument::Instrument;
-use crate::errors::{Result, ParseError};
+use crate::errors::{Diagnostics, Line, ParseErrorKind, Result, Severity,
+    ThrorganError};
+use crate::options::Options;
+use crate::output::Output;
+use crate::pitch::{KeySignature, parse_pitch, pitch_class};
+use crate::tuning::Tuning;
+use std::collections::HashMap;
 
 /// Stores parsing information about which part of the file we're in.
 /// `Instruments` is the instrument declaring stage, `Signatures` is for other
-/// things like tempo, and `Music` is the notes itself.
+/// things like tempo, `TempoMap` is for changes of tempo, and `Music` is the
+/// notes itself.
 enum Mode {
     Instruments,
     Signature,
+    TempoMap,
     Music
 }
 
@@ -21,7 +30,12 @@ enum Mode {
 /// what instruments it is written for
 pub struct Header {
     instruments: Vec<Instrument>,
+    aliases: HashMap<String, usize>, // Index of each named instrument
     tempo: u32,
+    tempo_changes: Vec<(u64, f64, bool)>, // Tick, tempo and whether to ramp
+    meter: MeterMap,
+    key: KeySignature,
+    tuning: Tuning,
     begin_music: usize,
     end_music: usize,
 }
@@ -29,31 +43,51 @@ pub struct Header {
 impl Header {
     /// Be able to generate an empty header to be loaded into
     fn empty() -> Header {
-        Header { instruments:Vec::new(), tempo: 0, begin_music: 0,
-            end_music: 0 }
+        Header { instruments:Vec::new(), aliases: HashMap::new(), tempo: 0,
+            tempo_changes: Vec::new(), meter: MeterMap::new(),
+            key: KeySignature::natural(), tuning: Tuning::equal(), begin_music: 0, end_music: 0 }
     }
 
     /// Check that the header has been fully filled
-    fn verify(self, name: &str) -> Result<Header> {
+    fn verify(&self, name: &str) -> Result<()> {
         if self.instruments.len() == 0 || self.tempo == 0 ||
             self.begin_music == 0 || self.end_music == 0 {
-            return Err(ParseError::ModeNotHit(name.to_string()).into());
+            return Err(ThrorganError::file(name, ParseErrorKind::ModeNotHit));
+        }
+        Ok(())
+    }
+
+    /// Find the instrument a music line refers to, either by its index in the
+    /// `#instruments` block or by its alias.
+    fn find_instrument(&self, reference: &str) -> Option<usize> {
+        match reference.parse::<usize>() {
+            Ok(index) if index < self.instruments.len() => Some(index),
+            Ok(_) => None,
+            Err(_) => self.aliases.get(reference).copied(),
         }
-        Ok(self)
     }
 }
 
-/// Makes and returns a header object for the text of a file
-pub fn get_header(content: &str, name: &str) -> Result<Header> {
+/// Makes and returns a header object for the text of a file. Problems are
+/// recorded in `diagnostics` and bad lines are skipped, so the header may be
+/// incomplete if any errors were found.
+pub fn get_header(content: &str, name: &str, diagnostics: &mut Diagnostics)
+-> Header {
     let mut mode : Option<Mode> = None;
     let mut ret = Header::empty();
+    let mut a4 = None;
+    // Tempo changes may be written in bars, so they are read once every time
+    // signature is known.
+    let mut tempo_lines = Vec::new();
+    let errors = diagnostics.count(Severity::Error);
 
-    for (num, line) in content.lines().enumerate() {
-        if line.is_empty() {
+    for (num, text) in content.lines().enumerate() {
+        if text.is_empty() {
             continue
         }
+        let line = Line::new(name, num, text);
 
-        if match line.chars().next() {
+        if match text.chars().next() {
             Some(c) => c,
             None => '?'
         } == '#' {
@@ -61,116 +95,525 @@ pub fn get_header(content: &str, name: &str) -> Result<Header> {
             if let Some(Mode::Music) = mode {
                 ret.end_music = num;
             }
-            mode = match &line[1..] {
+            mode = match &text[1..] {
                 "instruments" => Some(Mode::Instruments),
                 "signature" => Some(Mode::Signature),
+                "tempo-map" => Some(Mode::TempoMap),
                 "music" => {
                     ret.begin_music = num + 1;
                     Some(Mode::Music)
                 },
-                _ => None,
+                // The lines of unknown sections are skipped
+                _ => {
+                    diagnostics.warn(line.error(ParseErrorKind::InvalidMode,
+                        text));
+                    None
+                },
             };
         }
 
         else {
-            let mut items = line.split_whitespace();
-            match mode {
+            let result = match mode {
                 None => continue,
                 Some(ref m) => match m {
                     Mode::Instruments => {
-                        let instrument_name = match items.next() {
-                            None => return Err(ParseError::Unknown(
-                                name.to_string(), num).into()),
-                            Some(name) => name,
-                        };
-                        let vol = match items.next() {
-                            None => return Err(ParseError::Unknown(
-                                name.to_string(), num).into()),
-                            Some(v) => v,
-                        }.parse::<f32>()?;
-                        ret.instruments.push(
-                            Instrument::new(instrument_name, vol)?);
-                    }
-                    Mode::Signature => {
-                        let key_name = match items.next() {
-                            None => continue,
-                            Some(name) => name,
-                        };
-                        match key_name {
-                            "tempo" => ret.tempo = match items.next(){
-                                Some(n) => n,
-                                None => return Err(ParseError::KeyWithoutValue(
-                                    name.to_string(), num).into()),
-                            }.parse()?,
-                            _ => return Err(ParseError::InvalidKey(
-                                name.to_string(), num)
-                                .into()),
-                        };
-                    }
+                        let count = ret.instruments.len();
+                        let result = read_instrument(&mut ret, &line,
+                            diagnostics);
+                        // Keep the place of an instrument that could not be
+                        // read, so that later ones keep their numbers
+                        if ret.instruments.len() == count {
+                            ret.instruments.push(Instrument::empty());
+                        }
+                        result
+                    },
+                    Mode::Signature => read_signature(&mut ret, &mut a4, &line,
+                        diagnostics),
+                    Mode::TempoMap => read_tempo_change(&line, diagnostics)
+                        .map(|change| tempo_lines.extend(change)),
                     _ => continue
                 }
-            }
+            };
+            diagnostics.check(result);
         }
     }
     if ret.end_music == 0 {
         ret.end_music = content.lines().count();
     }
-    ret.verify(name)
+    if let Some(a4) = a4 {
+        ret.tuning.set_a4(a4);
+    }
+    for (line, time, bpm, ramp) in tempo_lines {
+        if let Some(tick) = diagnostics.check(parse_time(time, &ret.meter,
+            &line)) {
+            ret.tempo_changes.push((tick, bpm, ramp));
+        }
+    }
+    // A header with bad lines is likely to be missing what they set, which
+    // has already been reported
+    if diagnostics.count(Severity::Error) == errors {
+        diagnostics.check(ret.verify(name));
+    }
+    ret
 }
 
-pub fn generate(header: &Header, content: &str, name: &str, output_file: &str) 
--> Result<()> {
-    let mut bd = Breakdown::new(header.tempo, output_file);
-    let mut last_time = 0;
-    for (num, line) in content.lines().enumerate().skip(header.begin_music) {
-        let mut items = line.split_whitespace();
-        let inst_num = match items.next() {
-            Some(n) => n,
-            None => continue,
-        }.parse::<usize>()?;
-        
-        let note = match items.next() {
-            Some(n) => n,
-            None => return Err(ParseError::InvalidValue(name.to_string(), num)
-            .into()),
-        }.parse::<u32>()?;
-
-        let time = match items.next() {
-            Some(n) => n,
-            None => return Err(ParseError::InvalidValue(name.to_string(), num)
-            .into()),
-        }.parse::<u32>()?;
-        if time < last_time {
-            return Err(ParseError::InvalidNoteOrder(name.to_string(), num)
-            .into());
-        }
-        else{
-            last_time = time;
+/// Read a line of the `#instruments` section: `[alias =] instrument vol [pan]`.
+fn read_instrument(header: &mut Header, line: &Line,
+    diagnostics: &mut Diagnostics) -> Result<()> {
+    let mut items = line.text().split_whitespace();
+    let mut instrument_name = line.expect(items.next(),
+        ParseErrorKind::Unknown)?;
+    // Entries may be named as `alias = instrument vol`
+    if line.text().contains('=') {
+        let alias = instrument_name;
+        if items.next() != Some("=") || alias.parse::<usize>().is_ok() ||
+            header.aliases.contains_key(alias) {
+            return Err(line.error(ParseErrorKind::InvalidValue, alias));
         }
+        header.aliases.insert(alias.to_string(), header.instruments.len());
+        instrument_name = line.expect(items.next(), ParseErrorKind::Unknown)?;
+    }
+    let vol = line.parse::<f32>(line.expect(items.next(),
+        ParseErrorKind::Unknown)?, ParseErrorKind::InvalidValue)?;
+    let pan = match items.next() {
+        Some(pan) => parse_pan(pan, line)?,
+        None => 0.0,
+    };
+    diagnostics.unused(line, items.next());
+
+    // Instrument files that cannot be read are reported where the instrument
+    // is named
+    let mut instrument = Instrument::new(instrument_name, vol, diagnostics)
+        .map_err(|e| line.caused_by(ParseErrorKind::UnknownInstrument,
+        instrument_name, e))?;
+    instrument.set_pan(pan);
+    header.instruments.push(instrument);
+    Ok(())
+}
+
+/// Read a line of the `#signature` section, which sets one key of the header.
+/// The frequency of A4 is returned in `a4` to be set once the tuning is
+/// known.
+fn read_signature(header: &mut Header, a4: &mut Option<f32>, line: &Line,
+    diagnostics: &mut Diagnostics) -> Result<()> {
+    let mut items = line.text().split_whitespace();
+    let key_name = match items.next() {
+        None => return Ok(()),
+        Some(name) => name,
+    };
+    match key_name {
+        "tempo" => header.tempo = line.parse(line.expect(items.next(),
+            ParseErrorKind::KeyWithoutValue)?, ParseErrorKind::InvalidValue)?,
+        "key" => {
+            let value = line.expect(items.next(),
+                ParseErrorKind::KeyWithoutValue)?;
+            header.key = match KeySignature::from_name(value) {
+                Some(k) => k,
+                None => return Err(line.error(ParseErrorKind::InvalidValue,
+                    value)),
+            };
+        },
+        "time" => {
+            let value = line.expect(items.next(),
+                ParseErrorKind::KeyWithoutValue)?;
+            let (beats, unit) = match value.split_once('/') {
+                Some(t) => t,
+                None => return Err(line.error(ParseErrorKind::InvalidValue,
+                    value)),
+            };
+            let bar = match items.next() {
+                Some(b) => line.parse::<u64>(b, ParseErrorKind::InvalidValue)?,
+                None => 1,
+            };
+            if !header.meter.add(bar, line.parse(beats,
+                ParseErrorKind::InvalidValue)?, line.parse(unit,
+                ParseErrorKind::InvalidValue)?) {
+                return Err(line.error(ParseErrorKind::InvalidValue, value));
+            }
+        },
+        "tuning" => {
+            let values: Vec<&str> = items.by_ref().take(2).collect();
+            header.tuning = parse_tuning(&values, line)?;
+        },
+        "a4" => {
+            let value = line.expect(items.next(),
+                ParseErrorKind::KeyWithoutValue)?;
+            let freq = line.parse::<f32>(value, ParseErrorKind::InvalidValue)?;
+            if !freq.is_finite() || freq <= 0.0 {
+                return Err(line.error(ParseErrorKind::InvalidValue, value));
+            }
+            *a4 = Some(freq);
+        },
+        _ => return Err(line.error(ParseErrorKind::InvalidKey, key_name)),
+    };
+    diagnostics.unused(line, items.next());
+    Ok(())
+}
+
+/// Read a line of the `#tempo-map` section: `time bpm [ramp]`. The time is
+/// returned unread, since it may be written in bars.
+fn read_tempo_change<'a>(line: &Line<'a>, diagnostics: &mut Diagnostics)
+-> Result<Option<(Line<'a>, &'a str, f64, bool)>> {
+    let mut items = line.text().split_whitespace();
+    let time = match items.next() {
+        None => return Ok(None),
+        Some(t) => t,
+    };
+    let value = line.expect(items.next(), ParseErrorKind::KeyWithoutValue)?;
+    let bpm = line.parse::<f64>(value, ParseErrorKind::InvalidValue)?;
+    let ramp = match items.next() {
+        None => false,
+        Some("ramp") => true,
+        Some(key) => return Err(line.error(ParseErrorKind::InvalidKey, key)),
+    };
+    if !bpm.is_finite() || bpm <= 0.0 {
+        return Err(line.error(ParseErrorKind::InvalidValue, value));
+    }
+    diagnostics.unused(line, items.next());
+    Ok(Some((*line, time, bpm, ramp)))
+}
+
+/// Read the value of a `tuning` key on `line`: `equal`, `just` or
+/// `pythagorean` followed by an optional tonic, or the path of a Scala `.scl`
+/// file followed by an optional `.kbm` file.
+fn parse_tuning(values: &[&str], line: &Line) -> Result<Tuning> {
+    let tonic = match values.get(1) {
+        Some(t) => pitch_class(t),
+        None => Some(0),
+    };
+    match (values.first(), tonic) {
+        (None, _) => Err(line.missing(ParseErrorKind::KeyWithoutValue)),
+        (Some(&"equal"), _) if values.len() == 1 => Ok(Tuning::equal()),
+        (Some(&"just"), Some(tonic)) => Ok(Tuning::just(tonic)),
+        (Some(&"pythagorean"), Some(tonic)) => Ok(Tuning::pythagorean(tonic)),
+        // Scala files that cannot be read are reported where they are named
+        (Some(file), _) if file.ends_with(".scl") =>
+            Tuning::from_scala(file, values.get(1).copied()).map_err(
+                |e| match e {
+                ThrorganError::Io { .. } => line.caused_by(
+                    ParseErrorKind::InvalidValue, file, e),
+                e => e,
+            }),
+        (Some(&"just"), None) | (Some(&"pythagorean"), None) => Err(
+            line.error(ParseErrorKind::InvalidValue, values[1])),
+        (Some(value), _) => Err(line.error(ParseErrorKind::InvalidValue,
+            value)),
+    }
+}
+
+/// Read a number of beats written as a whole number (`2`), a decimal (`1.5`)
+/// or a fraction (`3/8`), and return it in ticks. Lengths finer than a tick
+/// are rounded to the nearest tick. Anything past `MAX_TICK` is rejected.
+fn parse_beats(text: &str) -> Option<u64> {
+    let ticks = match text.split_once('/') {
+        Some((num, den)) => {
+            let num = num.parse::<u64>().ok()?;
+            let den = den.parse::<u64>().ok()?;
+            if den == 0 {
+                return None;
+            }
+            let ticks = num.checked_mul(TICKS_PER_BEAT)?;
+            if ticks.is_multiple_of(den) {
+                ticks / den
+            } else {
+                (ticks as f64 / den as f64).round() as u64
+            }
+        },
+        None => {
+            let beats = text.parse::<f64>().ok()?;
+            if !beats.is_finite() || beats < 0.0 {
+                return None;
+            }
+            // Saturates for huge lengths, which are rejected below
+            (beats * TICKS_PER_BEAT as f64).round() as u64
+        },
+    };
+    Some(ticks).filter(|&ticks| ticks <= MAX_TICK)
+}
+
+/// Read a note onset written on `line` and return it in ticks. The onset is
+/// either a number of beats from the start of the song in any form accepted
+/// by `parse_beats`, or `bar:beat:tick` counting bars and beats of the time
+/// signature from 1 and ticks from 0. Onsets past `MAX_TICK` are rejected.
+fn parse_time(text: &str, meter: &MeterMap, line: &Line) -> Result<u64> {
+    let invalid = || line.error(ParseErrorKind::InvalidValue, text);
+    let fields: Vec<&str> = text.split(':').collect();
+    if fields.len() == 1 {
+        return parse_beats(text).ok_or_else(invalid);
+    }
+    if fields.len() > 3 {
+        return Err(invalid());
+    }
+    let bar = line.parse::<u64>(fields[0], ParseErrorKind::InvalidValue)?;
+    let beat = line.parse::<u64>(fields[1], ParseErrorKind::InvalidValue)?;
+    let tick = match fields.get(2) {
+        Some(t) => line.parse::<u64>(t, ParseErrorKind::InvalidValue)?,
+        None => 0,
+    };
+    match meter.position_to_tick(bar, beat, tick) {
+        Some(t) if t <= MAX_TICK => Ok(t),
+        Some(_) => Err(line.error(ParseErrorKind::PastEnd(bar), text)),
+        None => Err(line.error(ParseErrorKind::InvalidBeat(bar), text)),
+    }
+}
+
+/// Read a stereo position written on `line`, from -1 (left) to 1 (right).
+fn parse_pan(text: &str, line: &Line) -> Result<f32> {
+    let pan = line.parse::<f32>(text, ParseErrorKind::InvalidValue)?;
+    if !(-1.0..=1.0).contains(&pan) {
+        return Err(line.error(ParseErrorKind::InvalidValue, text));
+    }
+    Ok(pan)
+}
+
+/// One note read from the music section
+struct NoteEvent {
+    instrument: usize, // Index into `Header::instruments`
+    freq: f32,
+    time: u64, // Onset in ticks
+    length: u64, // Length in ticks
+    vol: f32,
+    pan: f32,
+}
+
+/// Read one line of the music section: `instrument pitch time length vol
+/// [pan]`. Blank lines give `None`. `last_time` is the onset of the note
+/// before, to check the order of notes in strict mode.
+fn read_note(header: &Header, line: &Line, last_time: &mut u64,
+    options: &Options, diagnostics: &mut Diagnostics)
+-> Result<Option<NoteEvent>> {
+    let mut items = line.text().split_whitespace();
+    let reference = match items.next() {
+        Some(n) => n,
+        None => return Ok(None),
+    };
+    let inst_num = match header.find_instrument(reference) {
+        Some(i) => i,
+        None => return Err(line.error(ParseErrorKind::UnknownInstrument,
+            reference)),
+    };
+
+    let pitch = line.expect(items.next(), ParseErrorKind::InvalidValue)?;
+    let freq = match parse_pitch(pitch, &header.key)
+        .and_then(|n| header.tuning.frequency(n)) {
+        Some(f) => f,
+        None => return Err(line.error(ParseErrorKind::InvalidValue, pitch)),
+    };
+
+    let time_text = line.expect(items.next(), ParseErrorKind::InvalidValue)?;
+    let time = parse_time(time_text, &header.meter, line)?;
+    let previous = std::mem::replace(last_time, time);
+    if options.strict && time < previous {
+        return Err(line.error(ParseErrorKind::InvalidNoteOrder(
+            header.meter.bar(time)), time_text));
+    }
+
+    let length_text = line.expect(items.next(), ParseErrorKind::InvalidValue)?;
+    let length = match parse_beats(length_text) {
+        Some(l) if l > 0 => l,
+        _ => return Err(line.error(ParseErrorKind::InvalidLength(
+            header.meter.bar(time)), length_text)),
+    };
+
+    let vol_text = line.expect(items.next(), ParseErrorKind::InvalidValue)?;
+    let vol = line.parse::<f32>(vol_text, ParseErrorKind::InvalidValue)?;
+    if vol > 1.0 {
+        return Err(line.error(ParseErrorKind::InvalidValue, vol_text));
+    }
+
+    // Notes take the pan of their instrument unless they give their own
+    let pan = match items.next() {
+        Some(p) => parse_pan(p, line)?,
+        None => header.instruments[inst_num].pan(),
+    };
+    diagnostics.unused(line, items.next());
+
+    Ok(Some(NoteEvent { instrument: inst_num, freq, time, length, vol, pan }))
+}
 
-        let note_length = match items.next() {
-            Some(n) => n,
-            None => return Err(ParseError::InvalidValue(name.to_string(), num)
-            .into()),
-        }.parse::<u32>()?;
-
-        let vol = match items.next() {
-            Some(n) => n,
-            None => return Err(ParseError::InvalidValue(name.to_string(), num)
-            .into()),
-        }.parse::<f32>()?;
-        if vol > 1.0 {
-            return Err(ParseError::InvalidValue(name.to_string(), num).into());
+/// Read every note of the music section and sort them by onset. Notes with the
+/// same onset keep the order in which they were written. In strict mode, notes
+/// written out of order are an error. Bad lines are recorded in `diagnostics`
+/// and skipped.
+fn get_notes(header: &Header, content: &str, name: &str, options: &Options,
+    diagnostics: &mut Diagnostics) -> Vec<NoteEvent> {
+    let mut ret = Vec::new();
+    let mut last_time = 0;
+    for (num, text) in content.lines().enumerate().take(header.end_music)
+        .skip(header.begin_music) {
+        let line = Line::new(name, num, text);
+        let note = read_note(header, &line, &mut last_time, options,
+            diagnostics);
+        if let Some(Some(note)) = diagnostics.check(note) {
+            ret.push(note);
         }
-        
-        let instrument = match header.instruments.get(inst_num) {
-            Some(inst) => inst,
-            None => return Err(ParseError::InvalidValue(name.to_string(), num)
-            .into())
-        };
-        bd.add_note(&instrument, note, time, note_length, vol);
     }
-    bd.push_all();
+    ret.sort_by_key(|event| event.time);
+    ret
+}
+
+/// A song read from a .thr file, ready to be rendered
+pub struct Song {
+    header: Header,
+    notes: Vec<NoteEvent>, // Sorted by onset
+}
+
+/// Read the header and every note of a song. Reading goes on past bad lines,
+/// so that every problem in the song and its instruments is found in one
+/// pass. Fails with all of them if any is an error, and returns the warnings
+/// with the song otherwise.
+pub fn read_song(content: &str, name: &str, options: &Options)
+-> Result<(Song, Diagnostics)> {
+    let mut diagnostics = Diagnostics::new();
+    let header = get_header(content, name, &mut diagnostics);
+    // Without a music section, every line would be read as a note
+    let notes = if header.begin_music > 0 {
+        get_notes(&header, content, name, options, &mut diagnostics)
+    } else {
+        Vec::new()
+    };
+    let diagnostics = diagnostics.into_result()?;
+    Ok((Song { header, notes }, diagnostics))
+}
+
+/// A summary of a song, as reported by `throrgan::info`.
+#[derive(Clone, Debug)]
+pub struct SongInfo {
+    pub instruments: usize,
+    pub notes: usize,
+    /// Starting tempo in beats per minute
+    pub tempo: u32,
+    /// Number of the bar in which the last note ends
+    pub bars: u64,
+    /// Length of the compiled song, including the release of the last notes
+    pub seconds: f64,
+}
+
+/// Make the tempo map of the song, timed at the sample rate in `options`.
+fn get_tempo_map(header: &Header, options: &Options) -> TempoMap {
+    let mut tempo_map = TempoMap::new(header.tempo as f64,
+        options.sample_rate);
+    for &(tick, bpm, ramp) in header.tempo_changes.iter() {
+        tempo_map.add(tick, bpm, ramp);
+    }
+    tempo_map
+}
+
+/// Summarize the song without rendering it.
+pub fn info(song: &Song, options: &Options) -> SongInfo {
+    let (header, notes) = (&song.header, &song.notes);
+    let tempo_map = get_tempo_map(header, options);
+    let last_tick = notes.iter().map(|n| n.time + n.length).max()
+        .unwrap_or(0);
+    let samples = notes.iter().map(|n| {
+        let release = header.instruments[n.instrument].reverb() as f64;
+        tempo_map.tick_to_sample(n.time + n.length) as f64 +
+            release * options.sample_rate as f64
+    }).fold(0.0, f64::max);
+    SongInfo { instruments: header.instruments.len(), notes: notes.len(),
+        tempo: header.tempo, bars: header.meter.bar(last_tick),
+        seconds: samples / options.sample_rate as f64 }
+}
+
+/// Render every note of the song into `output`.
+pub fn generate(song: &Song, output: Output, options: &Options) -> Result<()> {
+    let header = &song.header;
+    let tempo_map = get_tempo_map(header, options);
+    let mut bd = Breakdown::new(tempo_map, output, options);
+    for event in song.notes.iter() {
+        bd.add_note(&header.instruments[event.instrument], event.freq,
+            event.time, event.length, event.vol, event.pan)?;
+    }
+    bd.push_all()?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beats() {
+        assert_eq!(parse_beats("2"), Some(2 * TICKS_PER_BEAT));
+        assert_eq!(parse_beats("1.5"), Some(3 * TICKS_PER_BEAT / 2));
+        assert_eq!(parse_beats("1/3"), Some(TICKS_PER_BEAT / 3));
+        assert_eq!(parse_beats("1/0"), None);
+        assert_eq!(parse_beats("-1"), None);
+        // Far too long to render, and past where the arithmetic is safe
+        assert_eq!(parse_beats("999999999999999/1"), None);
+        assert_eq!(parse_beats("99999999999999999999"), None);
+        assert_eq!(parse_beats("1000001"), None);
+    }
+
+    /// Get the kind of every problem found in a song with `signature` and
+    /// `music` for its sections.
+    fn problems(signature: &str, music: &str) -> Vec<ParseErrorKind> {
+        let song = format!("#instruments\nsine 1\n\n#signature\ntempo 120\n{}\n\
+            \n#music\n{}\n", signature, music);
+        let mut diagnostics = Diagnostics::new();
+        let header = get_header(&song, "test", &mut diagnostics);
+        get_notes(&header, &song, "test", &Options::default(),
+            &mut diagnostics);
+        diagnostics.problems().iter().filter_map(|(_, problem)| match problem {
+            ThrorganError::Parse { kind, .. } => Some(kind.clone()),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn huge_meters() {
+        // Bars too long, or too many bars before a change, to count in ticks
+        for &time in &["time 1000000000000000/4", "time 3/4 1000000000000000"] {
+            assert_eq!(problems(time, "0 A4 1:1 1 1\n0 A4 9:1 1 1"),
+                [ParseErrorKind::InvalidValue]);
+        }
+    }
+
+    #[test]
+    fn bars_in_errors() {
+        let music = "0 A4 3:4 1 1\n0 A4 2:1 0 1\n0 A4 99999999:1 1 1\n\
+            0 A4 1000000000000000000:1 1 1";
+        assert_eq!(problems("time 3/4", music), [ParseErrorKind::InvalidBeat(3),
+            ParseErrorKind::InvalidLength(2), ParseErrorKind::PastEnd(99999999),
+            ParseErrorKind::PastEnd(1000000000000000000)]);
+    }
+    /// Read the notes of `song`, and get the errors found with them.
+    fn read_notes(song: &str) -> (Vec<NoteEvent>, usize) {
+        let mut diagnostics = Diagnostics::new();
+        let header = get_header(song, "test", &mut diagnostics);
+        let notes = get_notes(&header, song, "test", &Options::default(),
+            &mut diagnostics);
+        (notes, diagnostics.count(Severity::Error))
+    }
+
+    #[test]
+    fn notes_sorted() {
+        let (notes, errors) = read_notes("#instruments\nsine 1\n\n#signature\n\
+            tempo 120\n\n#music\n0 A4 2 1 1\n0 B4 0 1 1\n0 C5 1 1 1\n\
+            0 D5 0 1 1\n");
+        assert_eq!(errors, 0);
+        assert_eq!(notes.iter().map(|n| n.time / TICKS_PER_BEAT)
+            .collect::<Vec<_>>(), [0, 0, 1, 2]);
+        // Notes at the same onset keep the order they were written in
+        assert!(notes[0].freq < notes[1].freq);
+    }
+
+    #[test]
+    fn aliases() {
+        let song = "#instruments\nlead = sine 1\nsine 0.5\n\n#signature\n\
+            tempo 120\n\n#music\nlead A4 0 1 1\n0 A4 1 1 1\n1 A4 2 1 1\n";
+        let (notes, errors) = read_notes(song);
+        assert_eq!(errors, 0);
+        assert_eq!(notes.iter().map(|n| n.instrument).collect::<Vec<_>>(),
+            [0, 0, 1]);
+
+        // An instrument that cannot be loaded keeps its place
+        let song = song.replace("lead = sine 1", "bad = missing 1\n\
+            lead = sine 1");
+        let (notes, errors) = read_notes(&song);
+        assert_eq!(errors, 1);
+        assert_eq!(notes.iter().map(|n| n.instrument).collect::<Vec<_>>(),
+            [1, 0, 1]);
+    }
+}
@@ -0,0 +1,199 @@
+//! # Pitch
+//! 
+//! This file reads the pitches written in the music section. A pitch is either
+//! a MIDI note number or a scientific pitch name such as `A4`, `C#5` or `Bb3`,
+//! in which case the key signature supplies any accidental left unwritten.
+
+/// Semitones above C of each natural note, indexed from C.
+const NATURALS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// Letters in the order sharps are added to a key signature. Flats are added
+/// in the reverse order.
+const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6]; // F C G D A E B
+/// Lowest and highest note numbers a pitch may have, well past both ends of
+/// hearing but small enough for the tuning to work with.
+const LOWEST_NOTE: f32 = -128.0;
+const HIGHEST_NOTE: f32 = 255.0;
+
+/// Get the index of a note letter counting from C.
+fn letter_index(letter: char) -> Option<usize> {
+    match letter.to_ascii_uppercase() {
+        'C' => Some(0),
+        'D' => Some(1),
+        'E' => Some(2),
+        'F' => Some(3),
+        'G' => Some(4),
+        'A' => Some(5),
+        'B' => Some(6),
+        _ => None,
+    }
+}
+
+/// Read a note name without an octave, such as `F#` or `Bb`, as the index of
+/// its letter and the semitones its accidental adds.
+fn read_tonic(name: &str) -> Option<(usize, i32)> {
+    let mut chars = name.chars();
+    let letter = letter_index(chars.next()?)?;
+    let shift = match chars.as_str() {
+        "" => 0,
+        "#" => 1,
+        "b" => -1,
+        _ => return None,
+    };
+    Some((letter, shift))
+}
+
+/// Get the semitones above C of a note name without an octave, such as `F#`.
+pub fn pitch_class(name: &str) -> Option<i32> {
+    let (letter, shift) = read_tonic(name)?;
+    Some((NATURALS[letter] + shift).rem_euclid(12))
+}
+
+/// The accidental applied to each letter by the key of a piece
+#[derive(Clone, Copy)]
+pub struct KeySignature {
+    accidentals: [i32; 7], // Semitones added to each letter, indexed from C
+}
+
+impl KeySignature {
+    /// Make the key signature of C major, which has no accidentals.
+    pub fn natural() -> KeySignature {
+        KeySignature { accidentals: [0; 7] }
+    }
+
+    /// Make a key signature from the name of a key, such as `D`, `Bb` or
+    /// `F#m`. Returns `None` if the key does not exist.
+    pub fn from_name(name: &str) -> Option<KeySignature> {
+        let (tonic, minor) = match name.strip_suffix('m') {
+            Some(tonic) => (tonic, true),
+            None => (name, false),
+        };
+        let (letter, shift) = read_tonic(tonic)?;
+        // Count fifths from C major. A minor key has three fewer sharps than
+        // the major key on the same tonic.
+        let fifths: i32 = [0, 2, 4, -1, 1, 3, 5][letter] + 7 * shift -
+            if minor { 3 } else { 0 };
+        if !(-7..=7).contains(&fifths) {
+            return None;
+        }
+
+        let mut accidentals = [0; 7];
+        for i in 0..fifths.unsigned_abs() as usize {
+            if fifths > 0 {
+                accidentals[SHARP_ORDER[i]] = 1;
+            } else {
+                accidentals[SHARP_ORDER[6 - i]] = -1;
+            }
+        }
+        Some(KeySignature { accidentals })
+    }
+}
+
+/// Read a pitch as a MIDI note number, which may be fractional. The pitch is
+/// either the number itself or a letter, any number of `#` or `b` accidentals
+/// or a single `n` for natural, and an octave, where octave 4 starts at middle
+/// C. Letters written without an accidental take the accidental of `key`.
+/// Either form may end with an offset in cents, as in `A4+25` or `60-13.5`.
+/// Pitches below note -128 or above note 255 are rejected.
+pub fn parse_pitch(text: &str, key: &KeySignature) -> Option<f32> {
+    // The offset is the first sign that follows a digit, which keeps it apart
+    // from the sign of a negative octave such as `C-1`.
+    let split = text.char_indices().skip(1).find(|&(i, c)|
+        (c == '+' || c == '-') && text[..i].ends_with(|d: char|
+            d.is_ascii_digit()));
+    let (base, cents) = match split {
+        Some((i, _)) => (&text[..i], text[i..].trim_start_matches('+')
+            .parse::<f32>().ok()?),
+        None => (text, 0.0),
+    };
+    let note = match base.parse::<f32>() {
+        Ok(note) => note,
+        Err(_) => parse_name(base, key)? as f32,
+    } + cents / 100.0;
+    Some(note).filter(|note| (LOWEST_NOTE..=HIGHEST_NOTE).contains(note))
+}
+
+/// Read a pitch name such as `C#5` as a MIDI note number.
+fn parse_name(text: &str, key: &KeySignature) -> Option<i32> {
+    let mut chars = text.chars();
+    let letter = letter_index(chars.next()?)?;
+    let rest = chars.as_str();
+    let octave_start = rest.find(|c: char| c == '-' || c.is_ascii_digit())?;
+    let (accidentals, octave) = rest.split_at(octave_start);
+    let shift = match accidentals {
+        "" => key.accidentals[letter],
+        "n" => 0,
+        _ if accidentals.chars().all(|c| c == '#') => accidentals.len() as i32,
+        _ if accidentals.chars().all(|c| c == 'b') =>
+            -(accidentals.len() as i32),
+        _ => return None,
+    };
+    let octave = octave.parse::<i32>().ok()?;
+    octave.checked_add(1)?.checked_mul(12)?
+        .checked_add(NATURALS[letter] + shift)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Count the sharps and flats of a key signature.
+    fn count(key: &KeySignature) -> (usize, usize) {
+        (key.accidentals.iter().filter(|&&a| a > 0).count(),
+            key.accidentals.iter().filter(|&&a| a < 0).count())
+    }
+
+    #[test]
+    fn key_signatures() {
+        let key = |name| KeySignature::from_name(name).unwrap();
+        assert_eq!(count(&key("C")), (0, 0));
+        assert_eq!(count(&key("Am")), (0, 0));
+        assert_eq!(count(&key("D")), (2, 0));
+        assert_eq!(count(&key("Bb")), (0, 2));
+        assert_eq!(count(&key("Bbm")), (0, 5));
+        assert_eq!(count(&key("F#m")), (3, 0));
+        assert_eq!(count(&key("C#")), (7, 0));
+        assert!(KeySignature::from_name("Fb").is_none());
+        assert!(KeySignature::from_name("H").is_none());
+    }
+
+    #[test]
+    fn pitch_names() {
+        let natural = KeySignature::natural();
+        let d = KeySignature::from_name("D").unwrap();
+        assert_eq!(parse_pitch("A4", &natural), Some(69.0));
+        assert_eq!(parse_pitch("69", &natural), Some(69.0));
+        assert_eq!(parse_pitch("C#5", &natural), Some(73.0));
+        assert_eq!(parse_pitch("Bb3", &natural), Some(58.0));
+        // Unwritten accidentals come from the key, and `n` cancels them
+        assert_eq!(parse_pitch("F4", &d), Some(66.0));
+        assert_eq!(parse_pitch("Fn4", &d), Some(65.0));
+        assert_eq!(parse_pitch("Fb4", &d), Some(64.0));
+        assert_eq!(parse_pitch("Bb-1", &natural), Some(10.0));
+        assert_eq!(parse_pitch("H4", &natural), None);
+        assert_eq!(parse_pitch("C#b4", &natural), None);
+    }
+
+    #[test]
+    fn cents_offsets() {
+        let natural = KeySignature::natural();
+        assert_eq!(parse_pitch("A4+25", &natural), Some(69.25));
+        assert_eq!(parse_pitch("C-1+50", &natural), Some(0.5));
+        assert_eq!(parse_pitch("C-1", &natural), Some(0.0));
+        assert_eq!(parse_pitch("60-13.5", &natural), Some(60.0 - 0.135));
+        assert_eq!(parse_pitch("69.5", &natural), Some(69.5));
+        assert_eq!(parse_pitch("A4+", &natural), None);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let natural = KeySignature::natural();
+        assert_eq!(parse_pitch("-128", &natural), Some(-128.0));
+        assert_eq!(parse_pitch("255", &natural), Some(255.0));
+        assert_eq!(parse_pitch("255+1", &natural), None);
+        assert_eq!(parse_pitch("-1e30", &natural), None);
+        assert_eq!(parse_pitch("inf", &natural), None);
+        assert_eq!(parse_pitch("NaN", &natural), None);
+        assert_eq!(parse_pitch("C999999999", &natural), None);
+        assert_eq!(parse_pitch("C-999999999", &natural), None);
+    }
+}
@@ -0,0 +1,213 @@
+//! # Render
+//! 
+//! This file holds the backends that turn the sounds playing in a chunk into
+//! samples. `Spectral` builds the log-frequency spectrum of the chunk and
+//! resynthesizes it, while `Oscillator` sums one sinusoid per partial directly
+//! in the time domain.
+
+use crate::instrument::{Sound, Smear};
+use crate::options::Renderer;
+use std::f64::consts::TAU;
+
+pub const FREQ_SIZE: usize = 1024;
+const MIN_FREQ: f32 = 10.0;
+const MAX_FREQ: f32 = 10000.0;
+const MIN_POWER: f32 = 1e-6;
+const BAND_PARTIALS: usize = 32; // Oscillators used to fill a noise band
+
+/// Get the index of the log-frequency bin closest to `freq`, or `None` if the
+/// frequency lies outside of the range covered by the bins.
+pub fn frequency_to_index(freq: f32) -> Option<usize> {
+    if !(MIN_FREQ..=MAX_FREQ).contains(&freq) {
+        return None;
+    }
+    let index = (FREQ_SIZE as f32 * (f32::ln(freq) - f32::ln(MIN_FREQ)) /
+        (f32::ln(MAX_FREQ) - f32::ln(MIN_FREQ))).round() as usize;
+    Some(usize::min(index, FREQ_SIZE - 1))
+}
+
+/// Get the center frequency of the log-frequency bin `index`.
+pub fn index_to_frequency(index: usize) -> f32 {
+    MIN_FREQ * f32::powf(MAX_FREQ / MIN_FREQ, index as f32 / FREQ_SIZE as f32)
+}
+
+/// Get a fixed pseudo-random phase in cycles for the partial `index`, so that
+/// broad bands sound like noise rather than a click every time they begin.
+fn scatter(index: usize) -> f64 {
+    ((index as u64 * 2_654_435_761) % 65_536) as f64 / 65_536.0
+}
+
+/// A backend that renders one chunk of samples at a time.
+pub trait Render {
+    /// Start a chunk of `len` samples beginning at sample `start` of the song.
+    fn begin(&mut self, start: u64, len: usize);
+
+    /// Add `sounds` to the chunk, scaled by `envelope[t]` at each sample `t`.
+    fn add(&mut self, sounds: &[Sound], envelope: &[f32]);
+
+    /// Finish the chunk and return its samples.
+    fn finish(&mut self) -> Vec<f32>;
+}
+
+/// Make the backend selected by `renderer`, rendering `sample_rate` samples
+/// per second.
+pub fn new_renderer(renderer: Renderer, sample_rate: u32) -> Box<dyn Render> {
+    match renderer {
+        Renderer::Spectral => Box::new(Spectral::new(sample_rate)),
+        Renderer::Oscillator => Box::new(Oscillator::new(sample_rate)),
+    }
+}
+
+/// One bin of a spectrum: the bin, the amplitude deposited into it and how far
+/// the sound sits from the bin center in Hz
+type BinEntry = (usize, f32, f32);
+
+/// Renders through a spectrum over `FREQ_SIZE` log-frequency bins. Bin `w`
+/// drives a sinusoid at `index_to_frequency(w)` whose phase runs continuously
+/// across chunks, offset by how far each sound sits from the bin center. Only
+/// the bins each sound deposits into are kept, since a chunk rarely uses more
+/// than a few of them.
+pub struct Spectral {
+    frequencies: Vec<f32>, // Center frequency of each bin
+    entries: Vec<(Vec<BinEntry>, Vec<f32>)>, // Spectrum and envelope of each
+                                             // sound added
+    start: u64,
+    len: usize,
+    sample_rate: f64,
+}
+
+impl Spectral {
+    pub fn new(sample_rate: u32) -> Spectral {
+        Spectral { frequencies: (0..FREQ_SIZE).map(index_to_frequency)
+            .collect(), entries: Vec::new(), start: 0, len: 0,
+            sample_rate: sample_rate as f64 }
+    }
+
+    /// Spread `sounds` over the bins. The detune of each entry makes the
+    /// resynthesized pitch exact.
+    fn get_spectrum(&self, sounds: &[Sound]) -> Vec<BinEntry> {
+        let mut ret = Vec::new();
+        for sound in sounds {
+            match *sound.smear() {
+                Smear::Delta(mean) => {
+                    if let Some(w) = frequency_to_index(mean) {
+                        ret.push((w, sound.get_power(mean),
+                            mean - self.frequencies[w]));
+                    }
+                },
+                Smear::Gaussian(..) => {
+                    for (w, &freq) in self.frequencies.iter().enumerate() {
+                        let width = index_to_frequency(w + 1) - freq;
+                        let power = sound.get_power(freq) * width;
+                        if power > MIN_POWER {
+                            ret.push((w, power, 0.0));
+                        }
+                    }
+                },
+            }
+        }
+        ret
+    }
+}
+
+impl Render for Spectral {
+    fn begin(&mut self, start: u64, len: usize) {
+        self.start = start;
+        self.len = len;
+        self.entries.clear();
+    }
+
+    fn add(&mut self, sounds: &[Sound], envelope: &[f32]) {
+        let spectrum = self.get_spectrum(sounds);
+        if !spectrum.is_empty() {
+            self.entries.push((spectrum, envelope.to_vec()));
+        }
+    }
+
+    fn finish(&mut self) -> Vec<f32> {
+        let mut samples = vec![0.0; self.len];
+        for (spectrum, envelope) in self.entries.drain(..) {
+            for (w, power, detune) in spectrum {
+                let freq = self.frequencies[w] as f64;
+                for (t, sample) in samples.iter_mut().enumerate() {
+                    let time = (self.start + t as u64) as f64 /
+                        self.sample_rate;
+                    let cycles = (freq * time + scatter(w)).fract() +
+                        (detune as f64 * time).fract();
+                    *sample += power * envelope[t] * (cycles * TAU).cos() as f32;
+                }
+            }
+        }
+        samples
+    }
+}
+
+/// Renders each partial as its own sinusoid. A `Smear::Gaussian` partial is
+/// filled with `BAND_PARTIALS` sinusoids spaced along the Gaussian so that it
+/// sounds like a band of noise.
+pub struct Oscillator {
+    samples: Vec<f32>,
+    start: u64,
+    sample_rate: f64,
+}
+
+impl Oscillator {
+    pub fn new(sample_rate: u32) -> Oscillator {
+        Oscillator { samples: Vec::new(), start: 0,
+            sample_rate: sample_rate as f64 }
+    }
+
+    /// Get the frequency, amplitude and phase in cycles of every sinusoid
+    /// making up `sounds`.
+    fn get_partials(sounds: &[Sound]) -> Vec<(f64, f32, f64)> {
+        let mut ret = Vec::new();
+        for sound in sounds {
+            match *sound.smear() {
+                Smear::Delta(mean) => ret.push(
+                    (mean as f64, sound.get_power(mean), 0.0)),
+                Smear::Gaussian(mean, sigma) => {
+                    for i in 0..BAND_PARTIALS {
+                        // Place each sinusoid at the middle of an equally
+                        // probable slice of the Gaussian.
+                        let p = (i as f32 + 0.5) / BAND_PARTIALS as f32;
+                        let freq = mean + sigma * probit(p);
+                        ret.push((freq as f64, sound.vol() / BAND_PARTIALS as f32,
+                            scatter(ret.len())));
+                    }
+                },
+            }
+        }
+        ret
+    }
+}
+
+impl Render for Oscillator {
+    fn begin(&mut self, start: u64, len: usize) {
+        self.start = start;
+        self.samples = vec![0.0; len];
+    }
+
+    fn add(&mut self, sounds: &[Sound], envelope: &[f32]) {
+        for (freq, vol, phase) in Oscillator::get_partials(sounds) {
+            if freq <= 0.0 || freq >= self.sample_rate / 2.0 {
+                continue;
+            }
+            for (t, sample) in self.samples.iter_mut().enumerate() {
+                let time = (self.start + t as u64) as f64 / self.sample_rate;
+                let cycles = (freq * time + phase).fract();
+                *sample += vol * envelope[t] * (cycles * TAU).cos() as f32;
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+/// Approximate inverse of the standard normal cumulative distribution.
+fn probit(p: f32) -> f32 {
+    // Logistic approximation, accurate to about one percent in the body of the
+    // distribution, which is all a noise band needs.
+    f32::ln(p / (1.0 - p)) / 1.702
+}
@@ -0,0 +1,213 @@
+//! # Timeline
+//! 
+//! This file converts the positions of the music section into ticks, following
+//! the time signature of the piece, and ticks into samples, following its
+//! tempo.
+
+/// Resolution of note lengths. Divisible by every tuplet up to 9 and by 64, so
+/// common subdivisions land exactly on a tick.
+pub const TICKS_PER_BEAT: u64 = 20_160;
+
+/// Latest tick a note may start or end at, a million beats into the piece.
+/// Keeps tick and sample arithmetic far from overflowing.
+pub const MAX_TICK: u64 = 1_000_000 * TICKS_PER_BEAT;
+
+/// A bar where the time signature changes
+#[derive(Clone)]
+struct MeterEvent {
+    bar: u64, // Bars before this one, counting from 0
+    tick: u64, // Tick at which the bar starts
+    beats: u64, // Beats in each bar
+    beat_ticks: u64, // Ticks in each beat
+}
+
+/// The time signature of the piece at every bar
+pub struct MeterMap {
+    events: Vec<MeterEvent>, // Sorted by bar, the first at bar 0
+}
+
+impl MeterMap {
+    /// Make a meter map holding 4/4 for the whole piece.
+    pub fn new() -> MeterMap {
+        MeterMap { events: vec![MeterEvent { bar: 0, tick: 0, beats: 4,
+            beat_ticks: TICKS_PER_BEAT }] }
+    }
+
+    /// Change the time signature to `beats`/`unit` from bar `bar`, counting
+    /// from 1. A beat of the time signature lasts a `unit`th of a whole note,
+    /// which is four beats of the tempo. Returns `false`, leaving the map as it
+    /// was, if the time signature is not valid or its bars or the bars before
+    /// it are too long to count in ticks.
+    pub fn add(&mut self, bar: u64, beats: u64, unit: u64) -> bool {
+        if bar == 0 || beats == 0 || unit == 0 || !unit.is_power_of_two() ||
+            !(4 * TICKS_PER_BEAT).is_multiple_of(unit) ||
+            beats.checked_mul(4 * TICKS_PER_BEAT / unit).is_none() {
+            return false;
+        }
+        let event = MeterEvent { bar: bar - 1, tick: 0, beats,
+            beat_ticks: 4 * TICKS_PER_BEAT / unit };
+        let mut events = self.events.clone();
+        match events.binary_search_by_key(&event.bar, |e| e.bar) {
+            Ok(index) => events[index] = event,
+            Err(index) => events.insert(index, event),
+        }
+        for i in 1..events.len() {
+            let last = &events[i - 1];
+            events[i].tick = match (events[i].bar - last.bar)
+                .checked_mul(last.beats * last.beat_ticks)
+                .and_then(|ticks| ticks.checked_add(last.tick)) {
+                Some(tick) => tick,
+                None => return false,
+            };
+        }
+        self.events = events;
+        true
+    }
+
+    /// Get the tick at `tick` ticks into beat `beat` of bar `bar`, counting
+    /// bars and beats from 1, or `None` if the bar has no such beat. Positions
+    /// too late to count in ticks give `u64::MAX`.
+    pub fn position_to_tick(&self, bar: u64, beat: u64, tick: u64)
+    -> Option<u64> {
+        let bar = bar.checked_sub(1)?;
+        let beat = beat.checked_sub(1)?;
+        let index = self.events.partition_point(|e| e.bar <= bar) - 1;
+        let event = &self.events[index];
+        if beat >= event.beats || tick >= event.beat_ticks {
+            return None;
+        }
+        Some((bar - event.bar).saturating_mul(event.beats)
+            .saturating_add(beat).saturating_mul(event.beat_ticks)
+            .saturating_add(event.tick).saturating_add(tick))
+    }
+
+    /// Get the bar containing tick `tick`, counting from 1.
+    pub fn bar(&self, tick: u64) -> u64 {
+        let index = self.events.partition_point(|e| e.tick <= tick) - 1;
+        let event = &self.events[index];
+        event.bar + (tick - event.tick) / (event.beats * event.beat_ticks) + 1
+    }
+}
+
+/// A point where the tempo changes
+struct TempoEvent {
+    tick: u64,
+    bpm: f64,
+    ramp: bool, // Whether the tempo moves linearly to `bpm` from the previous
+                // event instead of jumping at `tick`
+    start: f64, // Seconds from the start of the song to `tick`
+}
+
+/// The tempo of the piece at every tick
+pub struct TempoMap {
+    events: Vec<TempoEvent>, // Sorted by tick, the first at tick 0
+    sample_rate: f64,
+}
+
+impl TempoMap {
+    /// Make a tempo map holding `bpm` for the whole piece, rendered at
+    /// `sample_rate` samples per second.
+    pub fn new(bpm: f64, sample_rate: u32) -> TempoMap {
+        TempoMap { events: vec![TempoEvent { tick: 0, bpm, ramp: false,
+            start: 0.0 }], sample_rate: sample_rate as f64 }
+    }
+
+    /// Change the tempo to `bpm` at `tick`, either at once or, if `ramp` is
+    /// set, gradually from the previous change. Changes may be added in any
+    /// order; changes at the same tick keep the order in which they were added.
+    pub fn add(&mut self, tick: u64, bpm: f64, ramp: bool) {
+        let index = self.events.partition_point(|e| e.tick <= tick);
+        self.events.insert(index, TempoEvent { tick, bpm, ramp, start: 0.0 });
+        for i in 1..self.events.len() {
+            self.events[i].start = self.events[i - 1].start +
+                self.segment_seconds(i - 1, self.events[i].tick);
+        }
+    }
+
+    /// Seconds from the event `index` to `tick`, which must not lie past the
+    /// next event.
+    fn segment_seconds(&self, index: usize, tick: u64) -> f64 {
+        let event = &self.events[index];
+        let beats = (tick - event.tick) as f64 / TICKS_PER_BEAT as f64;
+        match self.events.get(index + 1) {
+            Some(next) if next.ramp && next.tick > event.tick => {
+                // The tempo rises linearly in beats, so the time spent is the
+                // integral of 60 / tempo over the beats covered.
+                let length = (next.tick - event.tick) as f64 /
+                    TICKS_PER_BEAT as f64;
+                let slope = (next.bpm - event.bpm) / length;
+                if slope.abs() < 1e-9 {
+                    60.0 * beats / event.bpm
+                } else {
+                    60.0 / slope * f64::ln((event.bpm + slope * beats) /
+                        event.bpm)
+                }
+            },
+            _ => 60.0 * beats / event.bpm,
+        }
+    }
+
+    /// Get the sample at which tick `tick` of the song falls.
+    pub fn tick_to_sample(&self, tick: u64) -> u64 {
+        let index = self.events.partition_point(|e| e.tick <= tick) - 1;
+        let seconds = self.events[index].start +
+            self.segment_seconds(index, tick);
+        (seconds * self.sample_rate).round() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn meter_changes() {
+        let mut meter = MeterMap::new();
+        assert!(meter.add(1, 3, 4));
+        assert!(meter.add(3, 7, 8));
+        assert!(!meter.add(4, 5, 6));
+        assert_eq!(meter.position_to_tick(1, 1, 0), Some(0));
+        assert_eq!(meter.position_to_tick(2, 3, 0), Some(5 * TICKS_PER_BEAT));
+        assert_eq!(meter.position_to_tick(2, 4, 0), None);
+        assert_eq!(meter.position_to_tick(3, 7, 0),
+            Some(6 * TICKS_PER_BEAT + 3 * TICKS_PER_BEAT));
+        assert_eq!(meter.position_to_tick(4, 1, 0),
+            Some(6 * TICKS_PER_BEAT + 7 * TICKS_PER_BEAT / 2));
+        assert_eq!(meter.bar(0), 1);
+        assert_eq!(meter.bar(6 * TICKS_PER_BEAT - 1), 2);
+        assert_eq!(meter.bar(6 * TICKS_PER_BEAT), 3);
+        assert_eq!(meter.bar(10 * TICKS_PER_BEAT), 4);
+    }
+
+    #[test]
+    fn constant_tempo() {
+        let map = TempoMap::new(120.0, 44_100);
+        assert_eq!(map.tick_to_sample(0), 0);
+        assert_eq!(map.tick_to_sample(TICKS_PER_BEAT), 22_050);
+        assert_eq!(map.tick_to_sample(TICKS_PER_BEAT / 3), 7_350);
+        assert_eq!(map.tick_to_sample(3 * TICKS_PER_BEAT / 2), 33_075);
+        assert_eq!(map.tick_to_sample(1000 * TICKS_PER_BEAT), 22_050_000);
+
+        let map = TempoMap::new(60.0, 44_100);
+        assert_eq!(map.tick_to_sample(TICKS_PER_BEAT), 44_100);
+
+        let map = TempoMap::new(120.0, 48_000);
+        assert_eq!(map.tick_to_sample(TICKS_PER_BEAT), 24_000);
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let mut map = TempoMap::new(120.0, 44_100);
+        map.add(2 * TICKS_PER_BEAT, 60.0, false);
+        assert_eq!(map.tick_to_sample(2 * TICKS_PER_BEAT), 44_100);
+        assert_eq!(map.tick_to_sample(3 * TICKS_PER_BEAT), 88_200);
+
+        // Ramping from 60 to 120 BPM over 4 beats takes 4 ln 2 seconds.
+        let mut map = TempoMap::new(60.0, 44_100);
+        map.add(4 * TICKS_PER_BEAT, 120.0, true);
+        let expected = (4.0 * f64::ln(2.0) * 44_100.0).round();
+        assert_eq!(map.tick_to_sample(4 * TICKS_PER_BEAT), expected as u64);
+        assert_eq!(map.tick_to_sample(5 * TICKS_PER_BEAT),
+            expected as u64 + 22_050);
+    }
+}
@@ -0,0 +1,253 @@
+//! # Tuning
+//! 
+//! This file turns MIDI note numbers into frequencies. Every tuning is stored
+//! as a Scala scale and keyboard mapping: 12-TET, just intonation and
+//! Pythagorean tuning are built in, and any other tuning can be loaded from
+//! `.scl` and `.kbm` files.
+
+use crate::errors::{Line, ParseErrorKind, Result, ThrorganError};
+use std::fs;
+
+/// Ratios above the tonic of 5-limit just intonation
+const JUST: [(u32, u32); 12] = [(1, 1), (16, 15), (9, 8), (6, 5), (5, 4),
+    (4, 3), (45, 32), (3, 2), (8, 5), (5, 3), (9, 5), (15, 8)];
+/// Ratios above the tonic of Pythagorean tuning
+const PYTHAGOREAN: [(u32, u32); 12] = [(1, 1), (256, 243), (9, 8), (32, 27),
+    (81, 64), (4, 3), (729, 512), (3, 2), (128, 81), (27, 16), (16, 9),
+    (243, 128)];
+
+/// Convert a frequency ratio into cents.
+fn ratio_to_cents(num: f64, den: f64) -> f64 {
+    1200.0 * f64::log2(num / den)
+}
+
+pub struct Tuning {
+    scale: Vec<f64>, // Cents above the first degree of each degree of the scale
+    period: f64, // Cents spanned by the scale before it repeats
+    mapping: Vec<Option<i32>>, // Scale degree of each key, empty to map keys
+                               // to consecutive degrees
+    middle: i32, // Note which plays the first degree of the scale
+    octave_degree: i32, // Degrees the mapping moves each time it repeats
+    ref_note: i32, // Note tuned to `ref_freq`
+    ref_freq: f64,
+}
+
+impl Tuning {
+    /// Make a twelve-note tuning from ratios above `tonic`, given in semitones
+    /// above C, with A4 at 440 Hz.
+    fn from_ratios(ratios: &[(u32, u32)], tonic: i32) -> Tuning {
+        Tuning { scale: ratios.iter().map(
+            |&(n, d)| ratio_to_cents(n as f64, d as f64)).collect(),
+            period: 1200.0, mapping: Vec::new(), middle: 60 + tonic,
+            octave_degree: ratios.len() as i32, ref_note: 69, ref_freq: 440.0 }
+    }
+
+    /// Make twelve-tone equal temperament with A4 at 440 Hz.
+    pub fn equal() -> Tuning {
+        Tuning { scale: (0..12).map(|i| 100.0 * i as f64).collect(),
+            period: 1200.0, mapping: Vec::new(), middle: 60, octave_degree: 12,
+            ref_note: 69, ref_freq: 440.0 }
+    }
+
+    /// Make 5-limit just intonation on `tonic`, given in semitones above C.
+    pub fn just(tonic: i32) -> Tuning {
+        Tuning::from_ratios(&JUST, tonic)
+    }
+
+    /// Make Pythagorean tuning on `tonic`, given in semitones above C.
+    pub fn pythagorean(tonic: i32) -> Tuning {
+        Tuning::from_ratios(&PYTHAGOREAN, tonic)
+    }
+
+    /// Load a Scala scale file, and optionally a keyboard mapping file. With
+    /// no mapping, the first degree sits on middle C and A4 is at 440 Hz.
+    pub fn from_scala(scl_file: &str, kbm_file: Option<&str>)
+    -> Result<Tuning> {
+        let read = |path| fs::read_to_string(path)
+            .map_err(|e| ThrorganError::read(path, e));
+        let mut ret = read_scl(&read(scl_file)?, scl_file)?;
+        if let Some(kbm_file) = kbm_file {
+            read_kbm(&mut ret, &read(kbm_file)?, kbm_file)?;
+        }
+        Ok(ret)
+    }
+
+    /// Tune A4 to `freq` Hz, replacing any reference set by a mapping file.
+    pub fn set_a4(&mut self, freq: f32) {
+        self.ref_note = 69;
+        self.ref_freq = freq as f64;
+    }
+
+    /// Get the cents of key `note` above the first degree of the scale, or
+    /// `None` if the key is not mapped.
+    fn cents(&self, note: i32) -> Option<f64> {
+        let degree = if self.mapping.is_empty() {
+            note - self.middle
+        } else {
+            let size = self.mapping.len() as i32;
+            let offset = note - self.middle;
+            self.mapping[offset.rem_euclid(size) as usize]? +
+                offset.div_euclid(size) * self.octave_degree
+        };
+        let size = self.scale.len() as i32;
+        Some(self.scale[degree.rem_euclid(size) as usize] +
+            degree.div_euclid(size) as f64 * self.period)
+    }
+
+    /// Get the frequency of MIDI note `note`, or `None` if the tuning leaves
+    /// the note unmapped. A fractional part raises the note by that many
+    /// hundreds of cents.
+    pub fn frequency(&self, note: f32) -> Option<f32> {
+        let key = note.floor();
+        let cents = self.cents(key as i32)? + 100.0 * (note - key) as f64 -
+            self.cents(self.ref_note)?;
+        Some((self.ref_freq * f64::powf(2.0, cents / 1200.0)) as f32)
+    }
+}
+
+/// Get the lines of a Scala file that are not comments.
+fn scala_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content.lines().enumerate().filter(|(_, line)| !line.starts_with('!'))
+}
+
+/// Read the contents of a Scala scale file.
+fn read_scl(content: &str, name: &str) -> Result<Tuning> {
+    let missing = || ThrorganError::file(name, ParseErrorKind::ModeNotHit);
+    let mut lines = scala_lines(content).skip(1); // Skip the description
+    let (num, text) = lines.next().ok_or_else(missing)?;
+    let line = Line::new(name, num, text);
+    let count_text = text.trim();
+    let count = line.parse::<usize>(count_text,
+        ParseErrorKind::InvalidValue)?;
+    if count == 0 {
+        return Err(line.error(ParseErrorKind::InvalidValue, count_text));
+    }
+
+    let mut scale = vec![0.0];
+    for _ in 0..count {
+        let (num, text) = lines.next().ok_or_else(missing)?;
+        let line = Line::new(name, num, text);
+        let pitch = line.expect(text.split_whitespace().next(),
+            ParseErrorKind::InvalidValue)?;
+        let cents = if pitch.contains('.') {
+            pitch.parse::<f64>().ok()
+        } else {
+            let (n, d) = pitch.split_once('/').unwrap_or((pitch, "1"));
+            match (n.parse::<f64>(), d.parse::<f64>()) {
+                (Ok(n), Ok(d)) if n > 0.0 && d > 0.0 =>
+                    Some(ratio_to_cents(n, d)),
+                _ => None,
+            }
+        };
+        match cents {
+            Some(c) => scale.push(c),
+            None => return Err(line.error(ParseErrorKind::InvalidValue,
+                pitch)),
+        }
+    }
+
+    let period = scale.pop().unwrap_or(1200.0);
+    let mut ret = Tuning::equal();
+    ret.octave_degree = scale.len() as i32;
+    ret.scale = scale;
+    ret.period = period;
+    Ok(ret)
+}
+
+/// Read the contents of a Scala keyboard mapping file into `tuning`.
+fn read_kbm(tuning: &mut Tuning, content: &str, name: &str) -> Result<()> {
+    let mut values = Vec::new();
+    for (num, text) in scala_lines(content) {
+        let value = match text.split_whitespace().next() {
+            Some(v) => v,
+            None => continue,
+        };
+        values.push((Line::new(name, num, text), value));
+    }
+    if values.len() < 7 {
+        return Err(ThrorganError::file(name, ParseErrorKind::ModeNotHit));
+    }
+    let integer = |(line, value): (Line, &str)| line.parse::<i32>(value,
+        ParseErrorKind::InvalidValue);
+    let invalid = |(line, value): (Line, &str)| line.error(
+        ParseErrorKind::InvalidValue, value);
+
+    let size = integer(values[0])?;
+    tuning.middle = integer(values[3])?;
+    tuning.ref_note = integer(values[4])?;
+    tuning.ref_freq = match values[5].1.parse::<f64>() {
+        Ok(f) if f > 0.0 => f,
+        _ => return Err(invalid(values[5])),
+    };
+    tuning.octave_degree = integer(values[6])?;
+    if size < 0 || values.len() < 7 + size as usize {
+        return Err(invalid(values[0]));
+    }
+
+    tuning.mapping = Vec::new();
+    for &(line, value) in &values[7..7 + size as usize] {
+        tuning.mapping.push(match value {
+            "x" => None,
+            _ => Some(integer((line, value))?),
+        });
+    }
+    if tuning.cents(tuning.ref_note).is_none() {
+        return Err(invalid(values[4]));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-3 * b.abs(), "{} is not {}", a, b);
+    }
+
+    #[test]
+    fn equal() {
+        let mut tuning = Tuning::equal();
+        assert_close(tuning.frequency(69.0).unwrap(), 440.0);
+        assert_close(tuning.frequency(81.0).unwrap(), 880.0);
+        assert_close(tuning.frequency(60.0).unwrap(), 261.626);
+        tuning.set_a4(432.0);
+        assert_close(tuning.frequency(69.0).unwrap(), 432.0);
+        assert_close(tuning.frequency(57.0).unwrap(), 216.0);
+    }
+
+    #[test]
+    fn just() {
+        let tuning = Tuning::just(0);
+        let c = tuning.frequency(60.0).unwrap();
+        assert_close(tuning.frequency(64.0).unwrap() / c, 5.0 / 4.0);
+        assert_close(tuning.frequency(67.0).unwrap() / c, 3.0 / 2.0);
+        assert_close(tuning.frequency(72.0).unwrap() / c, 2.0);
+        assert_close(tuning.frequency(69.0).unwrap(), 440.0);
+    }
+
+    #[test]
+    fn scala() {
+        let scl = "! test.scl\n!\nThree notes\n 3\n!\n 400.0\n 7/4\n 2/1\n";
+        let tuning = read_scl(scl, "test.scl").unwrap();
+        let c = tuning.frequency(60.0).unwrap();
+        assert_close(tuning.frequency(61.0).unwrap() / c, 2f32.powf(1.0 / 3.0));
+        assert_close(tuning.frequency(62.0).unwrap() / c, 7.0 / 4.0);
+        assert_close(tuning.frequency(63.0).unwrap() / c, 2.0);
+        assert!(read_scl("Empty\n0\n", "empty.scl").is_err());
+        assert!(read_scl("Short\n2\n100.0\n", "short.scl").is_err());
+    }
+
+    #[test]
+    fn keyboard_mapping() {
+        let mut tuning = Tuning::equal();
+        let kbm = "! Every key but C#\n12\n0\n127\n60\n69\n440.0\n12\n\
+            0\nx\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n";
+        read_kbm(&mut tuning, kbm, "test.kbm").unwrap();
+        assert_close(tuning.frequency(69.0).unwrap(), 440.0);
+        assert_close(tuning.frequency(60.0).unwrap(), 261.626);
+        assert_eq!(tuning.frequency(61.0), None);
+        assert_eq!(tuning.frequency(73.0), None);
+        assert!(tuning.frequency(62.0).is_some());
+    }
+}
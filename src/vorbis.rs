@@ -0,0 +1,919 @@
+//! # Vorbis
+//!
+//! This file holds a lossy Ogg Vorbis encoder. Every block is a long block of
+//! 2048 samples and each channel is coded on its own. The spectrum of a block
+//! is described by a floor curve through fixed points, set well below the
+//! loudest part of the spectrum around each point but never more than 60 dB
+//! below the loudest part of the block, and the residue left over is quantized
+//! to whole steps of the floor. There is no psychoacoustic model, so files come
+//! out larger than those of a tuned encoder at the same quality, but any Vorbis
+//! decoder plays them.
+
+use crate::encode::{Encoder, Samples, StreamInfo};
+use crate::options::SampleFormat;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+use std::f64::consts::PI;
+use std::io::{self, Write};
+
+const BLOCK_EXPONENT: u32 = 11; // Log2 of the block size
+const BLOCK_SIZE: usize = 1 << BLOCK_EXPONENT;
+const SHORT_EXPONENT: u32 = 8; // Short blocks are declared but never used
+const SPECTRUM_SIZE: usize = BLOCK_SIZE / 2;
+
+const FLOOR_POINTS: usize = 32; // Points of the floor besides both ends
+const FLOOR_CLASS_SIZE: usize = 8; // Points in each partition of the floor
+const FLOOR_MULTIPLIER: i32 = 2; // Steps of the dB table per floor step
+const FLOOR_RANGE: i32 = 128; // Number of floor steps
+/// Ratio between the steps of the dB table of floor 1, whose top step is 1
+const DB_STEP: f64 = 0.062_961_31;
+/// Residue of the loudest coefficient near each floor point. Higher values
+/// keep more detail of loud parts of the spectrum.
+const RESOLUTION: f32 = 128.0;
+/// Ratio of the loudest coefficient of a block to the lowest its floor goes,
+/// 60 dB. Quieter parts of the spectrum are mostly dropped, which is where
+/// most of the size of a file is saved.
+const DYNAMIC_RANGE: f32 = 1000.0;
+/// Lowest floor of any block, about -100 dB
+const NOISE_FLOOR: f32 = 1e-5;
+
+const PARTITION_SIZE: usize = 16; // Coefficients in each partition
+/// Largest residue each class can hold. Class 0 is silent and the last class
+/// adds a coarse pass under the finest book.
+const CLASS_LIMITS: [i32; 7] = [0, 1, 2, 4, 8, 16, 247];
+/// Codebook of each pass of each class of the residue
+const CLASS_BOOKS: [[Option<usize>; 2]; 7] = [[None, None], [Some(2), None],
+    [Some(3), None], [Some(4), None], [Some(5), None], [Some(6), None],
+    [Some(7), Some(6)]];
+
+const PAGE_SIZE: usize = 4096; // Bytes of audio after which a page is written
+const SERIAL: u32 = 0x7468_726F; // Serial number of the stream, "thro"
+
+/// Collects a stream of bits, least significant first, as Vorbis packs them.
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u32, // Bits used in the last byte, from 0 to 7
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter { bytes: Vec::new(), bits: 0 }
+    }
+
+    /// Write the lowest `count` bits of `value`.
+    fn write(&mut self, value: u64, count: u32) {
+        for i in 0..count {
+            if self.bits == 0 {
+                self.bytes.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.bytes.last_mut().unwrap() |= bit << self.bits;
+            self.bits = (self.bits + 1) % 8;
+        }
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write(byte as u64, 8);
+        }
+    }
+}
+
+/// Get the number of bits needed to hold `value`.
+fn ilog(value: u32) -> u32 {
+    32 - value.leading_zeros()
+}
+
+/// Pack an integer in the float format of codebook headers.
+fn pack_float(value: i32) -> u64 {
+    let sign = if value < 0 { 1 << 31 } else { 0 };
+    sign | 788 << 21 | value.unsigned_abs() as u64
+}
+
+/// Get Huffman code lengths for symbols of the given weights.
+fn huffman_lengths(weights: &[f64]) -> Vec<u8> {
+    let max = weights.iter().cloned().fold(0.0, f64::max);
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> = weights.iter()
+        .enumerate().map(|(i, &w)| Reverse((
+            (w / max * 1e9).ceil().max(1.0) as u64, i)))
+        .collect();
+    let mut parents = vec![usize::MAX; weights.len()];
+    while heap.len() > 1 {
+        let Reverse((a, i)) = heap.pop().unwrap();
+        let Reverse((b, j)) = heap.pop().unwrap();
+        let node = parents.len();
+        parents.push(usize::MAX);
+        parents[i] = node;
+        parents[j] = node;
+        heap.push(Reverse((a + b, node)));
+    }
+    (0..weights.len()).map(|mut i| {
+        let mut depth = 0;
+        while parents[i] != usize::MAX {
+            i = parents[i];
+            depth += 1;
+        }
+        depth
+    }).collect()
+}
+
+/// Get the codeword of each entry of a codebook with the given lengths, in
+/// the order the Vorbis decoder assigns them: each entry takes the lowest
+/// codeword left free by the entries before it.
+fn codewords(lengths: &[u8]) -> Vec<u32> {
+    let mut marker = [0u32; 33]; // Next free codeword of each length
+    lengths.iter().map(|&length| {
+        let length = length as usize;
+        let mut entry = marker[length];
+        let word = entry;
+        for j in (1..=length).rev() {
+            if marker[j] & 1 != 0 {
+                marker[j] = if j == 1 { marker[1] + 1 } else {
+                    marker[j - 1] << 1 };
+                break;
+            }
+            marker[j] += 1;
+        }
+        for j in length + 1..33 {
+            if marker[j] >> 1 != entry {
+                break;
+            }
+            entry = marker[j];
+            marker[j] = marker[j - 1] << 1;
+        }
+        word
+    }).collect()
+}
+
+/// A Vorbis codebook. Entries of a lattice book stand for vectors of
+/// `dimensions` values, each of them `low + step * i` for `i` below `values`.
+struct Codebook {
+    dimensions: usize,
+    lengths: Vec<u8>,
+    words: Vec<u32>,
+    lattice: Option<(i32, i32, usize)>, // Low value, step and count
+}
+
+impl Codebook {
+    /// Make a book of scalar entries, coded according to their weights.
+    fn scalar(weights: &[f64]) -> Codebook {
+        let lengths = huffman_lengths(weights);
+        Codebook { dimensions: 1, words: codewords(&lengths), lengths,
+            lattice: None }
+    }
+
+    /// Make a lattice book of `values` values a step apart in each of
+    /// `dimensions` dimensions, centered on zero. Vectors are coded according
+    /// to the product of `weight` over their values.
+    fn lattice(dimensions: usize, values: usize, step: i32,
+        weight: fn(i32) -> f64) -> Codebook {
+        let low = -(values as i32 / 2) * step;
+        let entries = values.pow(dimensions as u32);
+        let weights: Vec<f64> = (0..entries).map(|entry| {
+            (0..dimensions).map(|i| {
+                let digit = entry / values.pow(i as u32) % values;
+                weight(low + step * digit as i32)
+            }).product()
+        }).collect();
+        let lengths = huffman_lengths(&weights);
+        Codebook { dimensions, words: codewords(&lengths), lengths,
+            lattice: Some((low, step, values)) }
+    }
+
+    fn write_header(&self, out: &mut BitWriter) {
+        out.write(0x56_4342, 24); // Sync pattern
+        out.write(self.dimensions as u64, 16);
+        out.write(self.lengths.len() as u64, 24);
+        out.write(0, 1); // Unordered
+        out.write(0, 1); // Not sparse
+        for &length in &self.lengths {
+            out.write(length as u64 - 1, 5);
+        }
+        match self.lattice {
+            None => out.write(0, 4),
+            Some((low, step, values)) => {
+                out.write(1, 4); // Lattice lookup
+                out.write(pack_float(low), 32);
+                out.write(pack_float(step), 32);
+                let bits = ilog(values as u32 - 1).max(1);
+                out.write(bits as u64 - 1, 4);
+                out.write(0, 1); // Values are not cumulative
+                for i in 0..values {
+                    out.write(i as u64, bits);
+                }
+            },
+        }
+    }
+
+    /// Write the codeword of `entry`, first bit first.
+    fn write(&self, out: &mut BitWriter, entry: usize) {
+        let word = self.words[entry];
+        for i in (0..self.lengths[entry]).rev() {
+            out.write((word >> i) as u64 & 1, 1);
+        }
+    }
+
+    /// Write the lattice vector closest to `values` and subtract it from them.
+    fn write_vector(&self, out: &mut BitWriter, values: &mut [i32]) {
+        let (low, step, count) = self.lattice.unwrap();
+        let mut entry = 0;
+        for (i, value) in values.iter_mut().enumerate() {
+            let digit = ((*value - low) as f32 / step as f32).round()
+                .clamp(0.0, count as f32 - 1.0) as usize;
+            *value -= low + step * digit as i32;
+            entry += digit * count.pow(i as u32);
+        }
+        self.write(out, entry);
+    }
+}
+
+/// Make every codebook of the stream. Book 0 codes floor values, book 1
+/// the classes of pairs of residue partitions and the rest residue values.
+fn codebooks() -> Vec<Codebook> {
+    let floor = (0..FLOOR_RANGE).map(|v| 1.0 / (1.0 + v as f64 / 4.0)
+        .powi(2)).collect::<Vec<_>>();
+    let class_weights = [8.0, 6.0, 4.0, 3.0, 2.0, 2.0, 1.0];
+    let classes = (0..class_weights.len().pow(2)).map(|entry|
+        class_weights[entry / 7] * class_weights[entry % 7])
+        .collect::<Vec<_>>();
+    let small = |v: i32| if v == 0 { 4.0 } else { 1.0 };
+    let falling = |v: i32| 1.0 / (1.0 + v.abs() as f64);
+    let flat = |_: i32| 1.0;
+    // Each codeword of the class book stands for the classes of two
+    // partitions, the first one the more significant
+    let classes = Codebook { dimensions: 2, ..Codebook::scalar(&classes) };
+    vec![Codebook::scalar(&floor), classes,
+        Codebook::lattice(4, 3, 1, small),
+        Codebook::lattice(2, 5, 1, falling),
+        Codebook::lattice(2, 9, 1, falling),
+        Codebook::lattice(2, 17, 1, falling),
+        Codebook::lattice(1, 33, 1, flat),
+        Codebook::lattice(1, 15, 33, falling)]
+}
+
+/// Get the value the floor curve takes at `x` on the line through `(x0, y0)`
+/// and `(x1, y1)`, rounded as the decoder does.
+fn render_point(x0: i32, y0: i32, x1: i32, y1: i32, x: i32) -> i32 {
+    let dy = y1 - y0;
+    let offset = dy.abs() * (x - x0) / (x1 - x0);
+    if dy < 0 { y0 - offset } else { y0 + offset }
+}
+
+/// Draw the floor curve from `(x0, y0)` up to but not including `x1` the way
+/// the decoder does.
+fn render_line(x0: i32, y0: i32, x1: i32, y1: i32, curve: &mut [i32]) {
+    let dy = y1 - y0;
+    let adx = x1 - x0;
+    let base = dy / adx;
+    let sy = if dy < 0 { base - 1 } else { base + 1 };
+    let ady = dy.abs() - base.abs() * adx;
+    let mut y = y0;
+    let mut err = 0;
+    curve[x0 as usize] = y;
+    for x in x0 + 1..x1 {
+        err += ady;
+        if err >= adx {
+            err -= adx;
+            y += sy;
+        } else {
+            y += base;
+        }
+        curve[x as usize] = y;
+    }
+}
+
+/// Get the value that codes floor step `y` where `predicted` was expected.
+fn floor_value(y: i32, predicted: i32) -> i32 {
+    let high_room = FLOOR_RANGE - predicted;
+    let low_room = predicted;
+    let room = 2 * high_room.min(low_room);
+    let diff = y - predicted;
+    if diff > 0 && 2 * diff < room {
+        2 * diff
+    } else if diff < 0 && -2 * diff - 1 < room {
+        -2 * diff - 1
+    } else if diff == 0 {
+        0
+    } else if high_room > low_room {
+        diff + low_room
+    } else {
+        high_room - diff - 1
+    }
+}
+
+/// The points of the floor. The list starts with both ends of the spectrum,
+/// and each later point lies between two earlier ones, from which the decoder
+/// predicts it.
+struct Floor {
+    xs: Vec<i32>,
+    neighbors: Vec<(usize, usize)>, // Closest earlier points on either side
+    order: Vec<usize>, // Indices of the points from left to right
+}
+
+impl Floor {
+    fn new() -> Floor {
+        // Points spread evenly over the octaves of the spectrum
+        let mut sorted: Vec<i32> = Vec::new();
+        for i in 0..FLOOR_POINTS {
+            let x = (4.0 * 250f64.powf(i as f64 / (FLOOR_POINTS - 1) as f64))
+                .round() as i32;
+            sorted.push(x.max(sorted.last().map_or(0, |&last| last + 1)));
+        }
+        // Take the middle point of each gap before the points inside it
+        let mut xs = vec![0, SPECTRUM_SIZE as i32];
+        let mut ranges = VecDeque::from(vec![(0, sorted.len())]);
+        while let Some((start, end)) = ranges.pop_front() {
+            if start < end {
+                let middle = (start + end) / 2;
+                xs.push(sorted[middle]);
+                ranges.push_back((start, middle));
+                ranges.push_back((middle + 1, end));
+            }
+        }
+
+        let neighbors = (0..xs.len()).map(|i| {
+            let low = (0..i).filter(|&j| xs[j] < xs[i])
+                .max_by_key(|&j| xs[j]).unwrap_or(0);
+            let high = (0..i).filter(|&j| xs[j] > xs[i])
+                .min_by_key(|&j| xs[j]).unwrap_or(0);
+            (low, high)
+        }).collect();
+        let mut order: Vec<usize> = (0..xs.len()).collect();
+        order.sort_by_key(|&i| xs[i]);
+        Floor { xs, neighbors, order }
+    }
+
+    fn write_header(&self, out: &mut BitWriter) {
+        let partitions = FLOOR_POINTS / FLOOR_CLASS_SIZE;
+        out.write(partitions as u64, 5);
+        for _ in 0..partitions {
+            out.write(0, 4); // Every partition is of class 0
+        }
+        out.write(FLOOR_CLASS_SIZE as u64 - 1, 3);
+        out.write(0, 2); // No subclasses
+        out.write(1, 8); // Book 0 codes the values
+        out.write(FLOOR_MULTIPLIER as u64 - 1, 2);
+        out.write(BLOCK_EXPONENT as u64 - 1, 4);
+        for &x in &self.xs[2..] {
+            out.write(x as u64, BLOCK_EXPONENT - 1);
+        }
+    }
+
+    /// Choose the floor of `spectrum`. Returns the values to write and the
+    /// floor the decoder will build from them.
+    fn fit(&self, spectrum: &[f32]) -> (Vec<i32>, Vec<f32>) {
+        let mut targets = vec![0; self.xs.len()];
+        let loudest = spectrum.iter().fold(0f32, |peak, c| peak.max(c.abs()));
+        let lowest = (loudest / DYNAMIC_RANGE).max(NOISE_FLOOR);
+        for (k, &i) in self.order.iter().enumerate() {
+            let start = self.xs[self.order[k.saturating_sub(1)]] as usize;
+            let end = self.order.get(k + 1).map_or(SPECTRUM_SIZE,
+                |&j| self.xs[j] as usize + 1).min(SPECTRUM_SIZE);
+            let peak = spectrum[start..end].iter()
+                .fold(0f32, |peak, c| peak.max(c.abs()));
+            let level = (peak / RESOLUTION).max(lowest);
+            let step = (255.0 + level.ln() as f64 / DB_STEP).ceil();
+            targets[i] = (step as i32 + FLOOR_MULTIPLIER - 1) /
+                FLOOR_MULTIPLIER;
+            targets[i] = targets[i].clamp(0, FLOOR_RANGE - 1);
+        }
+
+        // Code the points in order, predicting each from its neighbors
+        let mut values = targets.clone();
+        let mut ys = targets;
+        let mut used = vec![true; self.xs.len()];
+        for i in 2..self.xs.len() {
+            let (low, high) = self.neighbors[i];
+            let predicted = render_point(self.xs[low], ys[low],
+                self.xs[high], ys[high], self.xs[i]);
+            values[i] = floor_value(ys[i], predicted);
+            if values[i] == 0 {
+                ys[i] = predicted;
+                used[i] = false;
+            } else {
+                used[low] = true;
+                used[high] = true;
+            }
+        }
+
+        let mut curve = vec![0; SPECTRUM_SIZE + 1];
+        let (mut lx, mut ly) = (0, ys[0] * FLOOR_MULTIPLIER);
+        for &i in &self.order[1..] {
+            if used[i] {
+                let (hx, hy) = (self.xs[i], ys[i] * FLOOR_MULTIPLIER);
+                render_line(lx, ly, hx, hy, &mut curve);
+                lx = hx;
+                ly = hy;
+            }
+        }
+        let floor = curve[..SPECTRUM_SIZE].iter().map(|&y|
+            (DB_STEP * (y - 255) as f64).exp() as f32).collect();
+        (values, floor)
+    }
+}
+
+/// A forward MDCT of one block, computed with an FFT of a quarter of its
+/// length.
+struct Mdct {
+    window: Vec<f64>,
+    twiddles: Vec<(f64, f64)>, // Roots of unity of the FFT
+    before: Vec<(f64, f64)>, // Rotations applied before the FFT
+    after: Vec<(f64, f64)>, // Rotations applied after it
+}
+
+/// Get `e^(i * angle)`.
+fn rotation(angle: f64) -> (f64, f64) {
+    (angle.cos(), angle.sin())
+}
+
+fn multiply(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 * b.0 - a.1 * b.1, a.0 * b.1 + a.1 * b.0)
+}
+
+impl Mdct {
+    fn new() -> Mdct {
+        let n = BLOCK_SIZE as f64;
+        let m = SPECTRUM_SIZE as f64;
+        let quarter = SPECTRUM_SIZE / 2;
+        Mdct {
+            window: (0..BLOCK_SIZE).map(|i| (PI / 2.0 * ((i as f64 + 0.5) / n
+                * PI).sin().powi(2)).sin()).collect(),
+            twiddles: (0..quarter / 2).map(|k| rotation(-2.0 * PI * k as f64
+                / quarter as f64)).collect(),
+            before: (0..quarter).map(|i| rotation(-PI * i as f64 / m))
+                .collect(),
+            after: (0..quarter).map(|k| rotation(-PI * (k as f64 + 0.25) / m))
+                .collect(),
+        }
+    }
+
+    /// Transform `data` in place with a radix-2 FFT.
+    fn fft(&self, data: &mut [(f64, f64)]) {
+        let n = data.len();
+        let mut j = 0;
+        for i in 1..n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                data.swap(i, j);
+            }
+        }
+        let mut size = 2;
+        while size <= n {
+            let stride = n / size;
+            for start in (0..n).step_by(size) {
+                for k in 0..size / 2 {
+                    let t = multiply(data[start + k + size / 2],
+                        self.twiddles[k * stride]);
+                    let u = data[start + k];
+                    data[start + k] = (u.0 + t.0, u.1 + t.1);
+                    data[start + k + size / 2] = (u.0 - t.0, u.1 - t.1);
+                }
+            }
+            size *= 2;
+        }
+    }
+
+    /// Get the spectrum of a block of samples, scaled as the decoder expects.
+    fn transform(&self, block: &[f32]) -> Vec<f32> {
+        let m = SPECTRUM_SIZE;
+        let x: Vec<f64> = block.iter().zip(&self.window)
+            .map(|(&s, &w)| s as f64 * w).collect();
+        // Fold the four quarters of the block into a DCT-IV of half its length
+        let folded: Vec<f64> = (0..m).map(|i| if i < m / 2 {
+            -x[3 * m / 2 - 1 - i] - x[3 * m / 2 + i]
+        } else {
+            x[i - m / 2] - x[3 * m / 2 - 1 - i]
+        }).collect();
+        let mut data: Vec<(f64, f64)> = (0..m / 2).map(|i| multiply(
+            (folded[2 * i], folded[m - 1 - 2 * i]), self.before[i])).collect();
+        self.fft(&mut data);
+        let scale = 2.0 / m as f64;
+        let mut spectrum = vec![0.0; m];
+        for (k, &value) in data.iter().enumerate() {
+            let (re, im) = multiply(value, self.after[k]);
+            spectrum[2 * k] = (re * scale) as f32;
+            spectrum[m - 1 - 2 * k] = (-im * scale) as f32;
+        }
+        spectrum
+    }
+}
+
+/// Writes packets into Ogg pages.
+struct OggStream<W: Write> {
+    out: W,
+    sequence: u32, // Number of pages written
+    segments: Vec<u8>, // Lacing values of the page being filled
+    data: Vec<u8>,
+    granule: Option<u64>, // Position after the last packet ending on the page
+    continued: bool, // Whether the page starts inside a packet
+}
+
+/// Get the CRC-32 of `bytes` with polynomial 0x04C11DB7, used for pages.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in bytes {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04C1_1DB7 } else {
+                crc << 1 };
+        }
+    }
+    crc
+}
+
+impl<W: Write> OggStream<W> {
+    fn new(out: W) -> OggStream<W> {
+        OggStream { out, sequence: 0, segments: Vec::new(), data: Vec::new(),
+            granule: None, continued: false }
+    }
+
+    /// Add `packet` to the stream, after which `granule` samples have been
+    /// decoded.
+    fn packet(&mut self, packet: &[u8], granule: u64) -> io::Result<()> {
+        let mut rest = packet;
+        loop {
+            if self.segments.len() == 255 {
+                // A full page may also end just as a packet does
+                let inside = self.segments.last() == Some(&255);
+                self.flush(false)?;
+                self.continued = inside;
+            }
+            let length = rest.len().min(255);
+            self.segments.push(length as u8);
+            self.data.extend(&rest[..length]);
+            rest = &rest[length..];
+            if length < 255 {
+                break;
+            }
+        }
+        self.granule = Some(granule);
+        Ok(())
+    }
+
+    /// Write the page being filled. The last page marks the end of the
+    /// stream.
+    fn flush(&mut self, last: bool) -> io::Result<()> {
+        let mut page = b"OggS\0".to_vec();
+        let flags = self.continued as u8 | ((self.sequence == 0) as u8) << 1 |
+            (last as u8) << 2;
+        page.push(flags);
+        page.extend(&self.granule.map_or(u64::MAX, |g| g).to_le_bytes());
+        page.extend(&SERIAL.to_le_bytes());
+        page.extend(&self.sequence.to_le_bytes());
+        page.extend(&[0; 4]); // CRC, filled in below
+        page.push(self.segments.len() as u8);
+        page.append(&mut self.segments);
+        page.append(&mut self.data);
+        let crc = crc32(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.out.write_all(&page)?;
+        self.sequence += 1;
+        self.granule = None;
+        self.continued = false;
+        Ok(())
+    }
+}
+
+/// An Ogg Vorbis file. Samples are held back until a whole block is ready,
+/// and the last block is padded with silence that the decoder drops.
+pub struct Vorbis<W: Write> {
+    ogg: OggStream<W>,
+    info: StreamInfo,
+    books: Vec<Codebook>,
+    floor: Floor,
+    mdct: Mdct,
+    pending: Vec<Vec<f32>>, // Samples of each channel not yet coded
+    frames: u64, // Samples written to each channel so far
+    packets: u64, // Audio packets written
+}
+
+impl<W: Write> Vorbis<W> {
+    pub fn new(out: W, info: StreamInfo) -> io::Result<Vorbis<W>> {
+        let mut vorbis = Vorbis { ogg: OggStream::new(out), info,
+            books: codebooks(), floor: Floor::new(), mdct: Mdct::new(),
+            // The first block starts half a block before the song
+            pending: vec![vec![0.0; SPECTRUM_SIZE]; info.channels as usize],
+            frames: 0, packets: 0 };
+        vorbis.write_headers()?;
+        Ok(vorbis)
+    }
+
+    /// Write the identification, comment and setup headers, the first on a
+    /// page of its own.
+    fn write_headers(&mut self) -> io::Result<()> {
+        let mut id = BitWriter::new();
+        id.write_bytes(b"\x01vorbis");
+        id.write(0, 32); // Version
+        id.write(self.info.channels as u64, 8);
+        id.write(self.info.sample_rate as u64, 32);
+        for _ in 0..3 {
+            id.write(0, 32); // No bitrate hints
+        }
+        id.write(SHORT_EXPONENT as u64 | (BLOCK_EXPONENT as u64) << 4, 8);
+        id.write(1, 8); // Framing
+        self.ogg.packet(&id.bytes, 0)?;
+        self.ogg.flush(false)?;
+
+        let vendor = concat!("throrgan ", env!("CARGO_PKG_VERSION"));
+        let mut comment = BitWriter::new();
+        comment.write_bytes(b"\x03vorbis");
+        comment.write(vendor.len() as u64, 32);
+        comment.write_bytes(vendor.as_bytes());
+        comment.write(0, 32); // No comments
+        comment.write(1, 8); // Framing
+        self.ogg.packet(&comment.bytes, 0)?;
+
+        let mut setup = BitWriter::new();
+        setup.write_bytes(b"\x05vorbis");
+        setup.write(self.books.len() as u64 - 1, 8);
+        for book in &self.books {
+            book.write_header(&mut setup);
+        }
+        setup.write(0, 6); // One time domain transform, unused
+        setup.write(0, 16);
+        setup.write(0, 6); // One floor, of type 1
+        setup.write(1, 16);
+        self.floor.write_header(&mut setup);
+        setup.write(0, 6); // One residue, of type 1
+        setup.write(1, 16);
+        self.write_residue_header(&mut setup);
+        setup.write(0, 6); // One mapping, with no coupling
+        setup.write(0, 16);
+        setup.write(0, 1); // One submap
+        setup.write(0, 1); // No coupling
+        setup.write(0, 2);
+        setup.write(0, 8); // Time transform, floor and residue of the submap
+        setup.write(0, 8);
+        setup.write(0, 8);
+        setup.write(0, 6); // One mode, with long blocks
+        setup.write(1, 1);
+        setup.write(0, 16);
+        setup.write(0, 16);
+        setup.write(0, 8);
+        setup.write(1, 1); // Framing
+        self.ogg.packet(&setup.bytes, 0)?;
+        self.ogg.flush(false)
+    }
+
+    fn write_residue_header(&self, out: &mut BitWriter) {
+        out.write(0, 24); // The residue covers the whole spectrum
+        out.write(SPECTRUM_SIZE as u64, 24);
+        out.write(PARTITION_SIZE as u64 - 1, 24);
+        out.write(CLASS_BOOKS.len() as u64 - 1, 6);
+        out.write(1, 8); // Book 1 codes the classes
+        for books in &CLASS_BOOKS {
+            let passes = books.iter().enumerate().filter(|(_, b)| b.is_some())
+                .fold(0, |passes, (i, _)| passes | 1 << i);
+            out.write(passes, 3);
+            out.write(0, 1); // No passes past the third
+        }
+        for books in &CLASS_BOOKS {
+            for book in books.iter().flatten() {
+                out.write(*book as u64, 8);
+            }
+        }
+    }
+
+    /// Code the first block of pending samples and drop its first half.
+    fn write_block(&mut self) -> io::Result<()> {
+        let mut floors = Vec::new();
+        let mut residues = Vec::new();
+        for channel in &self.pending {
+            let spectrum = self.mdct.transform(&channel[..BLOCK_SIZE]);
+            let (values, floor) = self.floor.fit(&spectrum);
+            let limit = *CLASS_LIMITS.last().unwrap() as f32;
+            let residue: Vec<i32> = spectrum.iter().zip(&floor)
+                .map(|(c, f)| (c / f).round().clamp(-limit, limit) as i32)
+                .collect();
+            if residue.iter().any(|&r| r != 0) {
+                floors.push(Some(values));
+                residues.push(Some(residue));
+            } else {
+                floors.push(None);
+                residues.push(None);
+            }
+        }
+
+        let mut packet = BitWriter::new();
+        packet.write(0, 1); // Audio packet
+        packet.write(0b11, 2); // Long blocks before and after
+        for values in &floors {
+            match values {
+                None => packet.write(0, 1),
+                Some(values) => {
+                    packet.write(1, 1);
+                    let bits = ilog(FLOOR_RANGE as u32 - 1);
+                    packet.write(values[0] as u64, bits);
+                    packet.write(values[1] as u64, bits);
+                    for &value in &values[2..] {
+                        self.books[0].write(&mut packet, value as usize);
+                    }
+                },
+            }
+        }
+        self.write_residue(&mut packet, residues);
+
+        // Pages are written before they take a new packet, so that the last
+        // page always holds the last packet. The first packet, which gives no
+        // samples, has a page of its own so that decoders can tell where the
+        // song starts.
+        if self.packets == 1 || self.ogg.data.len() >= PAGE_SIZE {
+            self.ogg.flush(false)?;
+        }
+        let granule = (self.packets * SPECTRUM_SIZE as u64).min(self.frames);
+        self.ogg.packet(&packet.bytes, granule)?;
+        self.packets += 1;
+        for channel in self.pending.iter_mut() {
+            channel.drain(..SPECTRUM_SIZE);
+        }
+        Ok(())
+    }
+
+    /// Write the residue of each channel that has one, in the order the
+    /// decoder reads it: the classes of each pair of partitions are given
+    /// before the partitions themselves, and each pass of the cascade goes
+    /// over the whole spectrum.
+    fn write_residue(&self, out: &mut BitWriter,
+        mut residues: Vec<Option<Vec<i32>>>) {
+        let partitions = SPECTRUM_SIZE / PARTITION_SIZE;
+        let classes: Vec<Vec<usize>> = residues.iter().map(|residue|
+            residue.iter().flat_map(|r| r.chunks(PARTITION_SIZE)).map(|p| {
+                let peak = p.iter().map(|r| r.abs()).max().unwrap_or(0);
+                CLASS_LIMITS.iter().position(|&l| peak <= l).unwrap()
+            }).collect()).collect();
+        let per_word = self.books[1].dimensions;
+        for pass in 0..8 {
+            for start in (0..partitions).step_by(per_word) {
+                for classes in classes.iter().filter(|c| !c.is_empty()) {
+                    if pass == 0 {
+                        let word = classes[start..start + per_word].iter()
+                            .fold(0, |word, &c| word * CLASS_BOOKS.len() + c);
+                        self.books[1].write(out, word);
+                    }
+                }
+                for p in start..start + per_word {
+                    for (residue, classes) in residues.iter_mut()
+                        .zip(&classes) {
+                        let residue = match residue {
+                            Some(residue) => residue,
+                            None => continue,
+                        };
+                        let book = match CLASS_BOOKS[classes[p]].get(pass) {
+                            Some(Some(book)) => &self.books[*book],
+                            _ => continue,
+                        };
+                        let partition = &mut residue[p * PARTITION_SIZE..
+                            (p + 1) * PARTITION_SIZE];
+                        for vector in partition.chunks_mut(book.dimensions) {
+                            book.write_vector(out, vector);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<W: Write> Encoder for Vorbis<W> {
+    fn write(&mut self, samples: &Samples) -> io::Result<()> {
+        let channels = self.pending.len();
+        let samples: Vec<f32> = match samples {
+            Samples::Float(samples) => samples.clone(),
+            Samples::Int(samples) => {
+                let scale = match self.info.format {
+                    SampleFormat::Int24 => 8_388_607.0,
+                    _ => 32_767.0,
+                };
+                samples.iter().map(|&s| s as f32 / scale).collect()
+            },
+        };
+        for frame in samples.chunks_exact(channels) {
+            for (channel, &sample) in self.pending.iter_mut().zip(frame) {
+                channel.push(sample);
+            }
+        }
+        self.frames += (samples.len() / channels) as u64;
+        while self.pending[0].len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        // Each block after the first gives the decoder half a block of samples
+        let blocks = 1 + self.frames.div_ceil(SPECTRUM_SIZE as u64);
+        while self.packets < blocks {
+            for channel in self.pending.iter_mut() {
+                channel.resize(BLOCK_SIZE, 0.0);
+            }
+            self.write_block()?;
+        }
+        self.ogg.flush(true)?;
+        self.ogg.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lewton::inside_ogg::OggStreamReader;
+    use std::io::Cursor;
+
+    #[test]
+    fn checksum() {
+        // Ogg uses the CRC-32 of MPEG-2 without its initial value
+        assert_eq!(crc32(b"123456789"), 0x89A1_897F);
+    }
+
+    #[test]
+    fn codewords_fill_the_tree() {
+        assert_eq!(codewords(&[2, 4, 4, 4, 4, 2, 3, 3]),
+            [0b00, 0b0100, 0b0101, 0b0110, 0b0111, 0b10, 0b110, 0b111]);
+        let lengths = huffman_lengths(&[5.0, 1.0, 1.0, 2.0]);
+        assert_eq!(lengths, [1, 3, 3, 2]);
+    }
+
+    #[test]
+    fn mdct_matches_definition() {
+        let mdct = Mdct::new();
+        let block: Vec<f32> = (0..BLOCK_SIZE).map(|i|
+            ((i * 7919) % 1000) as f32 / 500.0 - 1.0).collect();
+        let spectrum = mdct.transform(&block);
+        let n = BLOCK_SIZE as f64;
+        for &k in &[0, 1, 17, 500, SPECTRUM_SIZE - 1] {
+            let expected: f64 = (0..BLOCK_SIZE).map(|i| {
+                block[i] as f64 * mdct.window[i] * (2.0 * PI / n *
+                    (i as f64 + 0.5 + n / 4.0) * (k as f64 + 0.5)).cos()
+            }).sum::<f64>() * 4.0 / n;
+            assert!((spectrum[k] as f64 - expected).abs() < 1e-4,
+                "coefficient {}: {} is not {}", k, spectrum[k], expected);
+        }
+    }
+
+    /// Encode `samples` of `channels` channels and decode them again.
+    fn round_trip(samples: &[f32], channels: u16) -> Vec<f32> {
+        let info = StreamInfo { sample_rate: 44_100, channels,
+            format: SampleFormat::Float32 };
+        let mut bytes = Vec::new();
+        {
+            let mut vorbis = Vorbis::new(&mut bytes, info).unwrap();
+            for piece in samples.chunks(1000 * channels as usize) {
+                vorbis.write(&Samples::Float(piece.to_vec())).unwrap();
+            }
+            vorbis.finish().unwrap();
+        }
+
+        let mut reader = OggStreamReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.ident_hdr.audio_channels, channels as u8);
+        assert_eq!(reader.ident_hdr.audio_sample_rate, 44_100);
+        let mut decoded = Vec::new();
+        while let Some(packet) = reader.read_dec_packet_itl().unwrap() {
+            decoded.extend(packet.iter().map(|&s| s as f32 / 32_768.0));
+        }
+        decoded
+    }
+
+    /// Get the ratio of the signal to the coding noise in dB.
+    fn snr(original: &[f32], decoded: &[f32]) -> f32 {
+        let signal: f32 = original.iter().map(|s| s * s).sum();
+        let noise: f32 = original.iter().zip(decoded)
+            .map(|(a, b)| (a - b) * (a - b)).sum();
+        10.0 * (signal / noise).log10()
+    }
+
+    #[test]
+    fn decodes_to_input() {
+        for &channels in &[1, 2] {
+            let frames = 20_000;
+            let samples: Vec<f32> = (0..frames * channels).map(|i| {
+                let t = (i / channels) as f32 / 44_100.0;
+                let c = (i % channels + 1) as f32;
+                0.4 * (t * 440.0 * c * std::f32::consts::TAU).sin() +
+                    0.2 * (t * 1234.5 * std::f32::consts::TAU).sin() *
+                    (t * 3.0).min(1.0)
+            }).collect();
+            let decoded = round_trip(&samples, channels as u16);
+            assert_eq!(decoded.len(), samples.len());
+            let quality = snr(&samples, &decoded);
+            assert!(quality > 40.0, "SNR of {} dB", quality);
+        }
+        assert!(round_trip(&[], 1).is_empty());
+        assert_eq!(round_trip(&[0.0; 300], 1), [0.0; 300]);
+    }
+
+    #[test]
+    fn silence_fills_pages() {
+        // Near-silent blocks code to a byte or two, so pages fill their 255
+        // segments long before their size limit and end on packet boundaries
+        let samples: Vec<f32> = (0..400_000).map(|i|
+            1e-7 * (i as f32 * 0.05).sin()).collect();
+        let decoded = round_trip(&samples, 1);
+        assert_eq!(decoded.len(), samples.len());
+        assert!(decoded.iter().all(|s| s.abs() < 1e-3));
+    }
+}